@@ -0,0 +1,1002 @@
+//! The HTTP API: shared state, handlers and routes.
+//!
+//! `main.rs` reads the configuration into an `AppState` and serves
+//! `configure` behind the app-wide middleware; the integration tests mount
+//! the same routes on a state of their own.
+
+use crate::auth::{ApiKeys, Principal};
+use crate::cache::{CacheDirectives, CacheKey, CachedAnswer, ResponseCache};
+use crate::config::Config;
+use crate::conversations::{Conversation, ConversationStore, Turn};
+use crate::fallback::{Answered, FailedAttempt, FallbackChain};
+use crate::health::{self, Check, ProviderProbes, Readiness};
+use crate::metrics::metrics;
+use crate::openai;
+use crate::params::{ParamPolicy, RequestParams};
+use crate::providers::{
+    ChatMessage, ChatOptions, ChatRequest, ChatResponse, ChatStream, LlmProvider, ProviderRegistry,
+    ProviderUnavailable, Usage,
+};
+use crate::rate_limit::RateLimits;
+use crate::sse;
+use crate::storage::{AnswerMeta, Storage, UsageFilter};
+use crate::summarize::{SummarizeError, Summarizer};
+use crate::templates::{self, PromptLibrary};
+use crate::usage::{PriceTable, UsageReport};
+use actix_files::Files;
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::warn;
+
+#[derive(Deserialize)]
+pub(crate) struct CompletionRequest {
+    pub(crate) question: String,
+    /// Name of a prompt template; the library default when omitted.
+    pub(crate) template: Option<String>,
+    /// Overrides for the template's declared variables.
+    #[serde(default)]
+    pub(crate) variables: HashMap<String, String>,
+    /// Name of a multi-step pipeline to run instead of a single template.
+    pub(crate) mode: Option<String>,
+    /// Include intermediate pipeline step outputs in the response.
+    #[serde(default)]
+    pub(crate) debug: bool,
+    /// Model and sampling overrides, checked against `ParamPolicy`.
+    #[serde(flatten)]
+    pub(crate) params: RequestParams,
+}
+
+#[derive(Serialize)]
+struct CompletionResponse {
+    answer: String,
+    /// The provider and model that produced `answer`, after any fallbacks.
+    provider: String,
+    model: String,
+    /// Served from the response cache rather than the provider.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+    /// Tokens used by every upstream call behind `answer`, and their cost.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<UsageReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed_attempts: Vec<FailedAttempt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    steps: Option<Vec<StepOutput>>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StepOutput {
+    pub(crate) name: String,
+    pub(crate) output: String,
+}
+
+#[derive(Deserialize)]
+struct CreateConversationRequest {
+    #[serde(default = "default_conversation_provider")]
+    provider: String,
+}
+
+fn default_conversation_provider() -> String {
+    "openrouter".to_string()
+}
+
+#[derive(Serialize)]
+struct CreateConversationResponse {
+    conversation_id: String,
+    provider: String,
+}
+
+#[derive(Deserialize)]
+struct SummarizeRequest {
+    text: String,
+    /// Also return the summary of every chunk.
+    #[serde(default)]
+    include_chunks: bool,
+    #[serde(flatten)]
+    params: RequestParams,
+}
+
+#[derive(Serialize)]
+struct SummarizeResponse {
+    summary: String,
+    chunk_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_summaries: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<UsageReport>,
+}
+
+/// Everything the handlers share, cloned into every worker.
+#[derive(Clone)]
+pub struct AppState {
+    pub providers: ProviderRegistry,
+    pub templates: Arc<PromptLibrary>,
+    pub conversations: Arc<ConversationStore>,
+    pub storage: Arc<Storage>,
+    /// `None` when OpenRouter, which it runs on, is disabled.
+    pub summarizer: Option<Arc<Summarizer>>,
+    pub params: Arc<ParamPolicy>,
+    pub fallbacks: Arc<FallbackChain>,
+    /// `None` when RESPONSE_CACHE is off.
+    pub cache: Option<Arc<ResponseCache>>,
+    pub prices: Arc<PriceTable>,
+    pub probes: Arc<ProviderProbes>,
+    pub api_keys: Arc<ApiKeys>,
+    pub rate_limits: RateLimits,
+    pub config: Arc<Config>,
+}
+
+impl AppState {
+    /// The provider called `name`, or a 503 if it is disabled.
+    pub(crate) fn provider(&self, name: &str) -> Result<Arc<dyn LlmProvider>, ProviderUnavailable> {
+        self.providers.lookup(name)
+    }
+
+    /// Validates `params` against the server policy and the models `principal` may use.
+    pub(crate) fn check_params(
+        &self,
+        principal: &Principal,
+        provider: &dyn LlmProvider,
+        params: &RequestParams,
+    ) -> ActixResult<ChatOptions> {
+        let options = self.params.check(provider, params)?;
+        principal.check_model(options.model.as_deref().unwrap_or(provider.default_model()))?;
+        Ok(options)
+    }
+
+    /// Prices the `usage` of one upstream call and adds it to `principal`'s
+    /// daily totals in the background.
+    pub(crate) fn account(
+        &self,
+        principal: &Principal,
+        provider: &str,
+        model: &str,
+        usage: Option<Usage>,
+    ) -> Option<UsageReport> {
+        let (report, record) = self.usage_report(principal, provider, model, usage);
+        actix_rt::spawn(record);
+        report
+    }
+
+    /// Like `account`, but waits until the totals are stored, for callers
+    /// that exit right after.
+    pub(crate) async fn account_now(
+        &self,
+        principal: &Principal,
+        provider: &str,
+        model: &str,
+        usage: Option<Usage>,
+    ) -> Option<UsageReport> {
+        let (report, record) = self.usage_report(principal, provider, model, usage);
+        record.await;
+        report
+    }
+
+    /// Prices `usage` and returns the future adding it to the daily totals.
+    fn usage_report(
+        &self,
+        principal: &Principal,
+        provider: &str,
+        model: &str,
+        usage: Option<Usage>,
+    ) -> (Option<UsageReport>, impl Future<Output = ()> + 'static) {
+        if let Some(usage) = &usage {
+            metrics().add_tokens(provider, model, usage);
+        }
+        let cost_usd = usage.and_then(|usage| self.prices.cost(model, &usage));
+        let storage = self.storage.clone();
+        let (label, provider, model) = (
+            principal.label.clone(),
+            provider.to_string(),
+            model.to_string(),
+        );
+        let record = async move {
+            let recorded = web::block(move || {
+                storage.record_usage(&label, &provider, &model, usage, cost_usd)
+            })
+            .await;
+            match recorded {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!(error = %e, "failed to record usage"),
+                Err(e) => warn!(error = %e, "failed to record usage"),
+            }
+        };
+        (usage.map(|usage| UsageReport { usage, cost_usd }), record)
+    }
+}
+
+/// Stands in for an empty answer; never cached.
+const NO_ANSWER: &str = "No answer received";
+
+/// Sends `request` to `provider`, or its fallbacks if it fails, filling in a
+/// placeholder for empty answers.
+pub(crate) async fn ask(
+    state: &AppState,
+    principal: &Principal,
+    provider: Arc<dyn LlmProvider>,
+    request: ChatRequest,
+) -> ActixResult<Answered<ChatResponse>> {
+    let mut answered = ask_verbatim(state, principal, provider, request).await?;
+    let response = &mut answered.value;
+    if response.content.is_empty() && response.tool_calls.is_empty() {
+        response.content = NO_ANSWER.to_string();
+    }
+    Ok(answered)
+}
+
+/// Like `ask`, but returns an empty answer as it is, for clients that expect
+/// the upstream's response unchanged.
+pub(crate) async fn ask_verbatim(
+    state: &AppState,
+    principal: &Principal,
+    provider: Arc<dyn LlmProvider>,
+    request: ChatRequest,
+) -> ActixResult<Answered<ChatResponse>> {
+    let mut answered = state
+        .fallbacks
+        .run(
+            &state.providers,
+            &state.params,
+            principal,
+            provider,
+            request,
+            |provider, request| async move { provider.chat(request).await },
+        )
+        .await?;
+
+    // Prefer the id the upstream reports, e.g. the model an alias resolved to.
+    if !answered.value.model.is_empty() {
+        answered.model = answered.value.model.clone();
+    }
+    Ok(answered)
+}
+
+/// Opens a streamed chat for `request` on `provider`, or its fallbacks if it
+/// fails. Fallbacks only apply until the stream opens.
+pub(crate) async fn ask_stream(
+    state: &AppState,
+    principal: &Principal,
+    provider: Arc<dyn LlmProvider>,
+    request: ChatRequest,
+) -> ActixResult<Answered<ChatStream>> {
+    let answered = state
+        .fallbacks
+        .run(
+            &state.providers,
+            &state.params,
+            principal,
+            provider,
+            request,
+            |provider, request| async move { provider.chat_stream(request).await },
+        )
+        .await?;
+    Ok(answered)
+}
+
+/// Answers `req` on `provider` and wraps the result in a `CompletionResponse`.
+///
+/// Answers are served from and stored in the response cache as `directives`
+/// allow; debug requests, whose steps are not cached, always go upstream.
+async fn answer_with(
+    state: &AppState,
+    principal: &Principal,
+    provider: Arc<dyn LlmProvider>,
+    req: &CompletionRequest,
+    directives: CacheDirectives,
+) -> ActixResult<HttpResponse> {
+    let options = state.check_params(principal, provider.as_ref(), &req.params)?;
+    let cache = state.cache.clone().filter(|_| !req.debug);
+    let cache_key = cache.as_ref().map(|_| {
+        CacheKey {
+            provider: provider.name(),
+            model: options.model.as_deref().unwrap_or(provider.default_model()),
+            question: &req.question,
+            template: req.template.as_deref(),
+            mode: req.mode.as_deref(),
+            variables: req
+                .variables
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<BTreeMap<_, _>>(),
+            options: &options,
+        }
+        .digest()
+    });
+
+    if let (Some(cache), Some(key), true) = (&cache, &cache_key, directives.lookup) {
+        let (cache, key) = (cache.clone(), key.clone());
+        let hit = web::block(move || cache.get(&key)).await?;
+        metrics().cache_lookup(hit.is_some());
+        if let Some(hit) = hit {
+            return Ok(HttpResponse::Ok().json(CompletionResponse {
+                answer: hit.answer,
+                provider: hit.provider,
+                model: hit.model,
+                cached: true,
+                usage: None,
+                failed_attempts: Vec::new(),
+                steps: None,
+            }));
+        }
+    }
+
+    let prepared = completion_request(state, principal, &provider, options, req).await?;
+    let answered = ask(state, principal, provider, prepared.request).await?;
+    let usage = state.account(
+        principal,
+        answered.provider,
+        &answered.model,
+        answered.value.usage,
+    );
+    let mut failed_attempts = prepared.failed_attempts;
+    failed_attempts.extend(answered.failed_attempts);
+    let response = CompletionResponse {
+        answer: answered.value.content,
+        provider: answered.provider.to_string(),
+        model: answered.model,
+        cached: false,
+        usage: UsageReport::total(prepared.usage.into_iter().chain([usage])),
+        failed_attempts,
+        steps: req.debug.then_some(prepared.steps),
+    };
+
+    if let (Some(cache), Some(key), true) = (cache, cache_key, directives.store) {
+        if response.answer != NO_ANSWER {
+            let value = CachedAnswer {
+                answer: response.answer.clone(),
+                provider: response.provider.clone(),
+                model: response.model.clone(),
+            };
+            web::block(move || cache.put(&key, value)).await?;
+        }
+    }
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// The request for the final answer plus what earlier pipeline steps produced.
+pub(crate) struct PreparedCompletion {
+    pub(crate) request: ChatRequest,
+    pub(crate) steps: Vec<StepOutput>,
+    /// Upstream failures the earlier steps recovered from.
+    pub(crate) failed_attempts: Vec<FailedAttempt>,
+    /// Tokens each earlier step used.
+    pub(crate) usage: Vec<Option<UsageReport>>,
+    /// Adding each earlier step's usage to the daily totals; runs on its
+    /// own, callers that exit right after await it.
+    pub(crate) recording: Vec<actix_rt::task::JoinHandle<()>>,
+}
+
+/// Builds the final provider request for `req`.
+///
+/// With a `mode`, every pipeline step but the last is run here and returned
+/// alongside the request for the last step, so callers can answer or stream
+/// that final step like any single-template request.
+pub(crate) async fn completion_request(
+    state: &AppState,
+    principal: &Principal,
+    provider: &Arc<dyn LlmProvider>,
+    options: ChatOptions,
+    req: &CompletionRequest,
+) -> ActixResult<PreparedCompletion> {
+    let Some(mode) = req.mode.as_deref() else {
+        return Ok(PreparedCompletion {
+            request: ChatRequest::new(prompt_messages(state, req)?).with_options(options),
+            steps: Vec::new(),
+            failed_attempts: Vec::new(),
+            usage: Vec::new(),
+            recording: Vec::new(),
+        });
+    };
+    if req.template.is_some() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Use either template or mode, not both",
+        ));
+    }
+
+    let pipeline = state.templates.pipeline(mode)?;
+    let (last, earlier) = pipeline
+        .steps()
+        .split_last()
+        .expect("pipelines are validated to have steps");
+
+    // `text` is the previous output, as in llm-chain's sequential Chain. The
+    // Chain itself is not used: it only carries `text` between steps, so
+    // `{{draft}}`-style step outputs would fail to render, and it runs the
+    // last step too, which callers need to stream or answer through `ask`.
+    let mut outputs: Vec<StepOutput> = Vec::new();
+    let mut failed_attempts = Vec::new();
+    let mut usage = Vec::new();
+    let mut recording = Vec::new();
+    for step in earlier {
+        let inputs = step_inputs(&req.question, &outputs);
+        let messages = step
+            .template
+            .render_with(&req.question, &req.variables, &inputs)?;
+        let request = ChatRequest::new(messages).with_options(options.clone());
+        let answered = ask(state, principal, provider.clone(), request).await?;
+        let (report, record) = state.usage_report(
+            principal,
+            answered.provider,
+            &answered.model,
+            answered.value.usage,
+        );
+        usage.push(report);
+        recording.push(actix_rt::spawn(record));
+        failed_attempts.extend(answered.failed_attempts);
+        outputs.push(StepOutput {
+            name: step.name.clone(),
+            output: answered.value.content,
+        });
+    }
+
+    let inputs = step_inputs(&req.question, &outputs);
+    let messages = last
+        .template
+        .render_with(&req.question, &req.variables, &inputs)?;
+    Ok(PreparedCompletion {
+        request: ChatRequest::new(messages).with_options(options),
+        steps: outputs,
+        failed_attempts,
+        usage,
+        recording,
+    })
+}
+
+/// `text` (previous output, or the question for the first step) plus each output by step name.
+fn step_inputs<'a>(question: &'a str, outputs: &'a [StepOutput]) -> Vec<(&'a str, &'a str)> {
+    let text = outputs.last().map_or(question, |o| o.output.as_str());
+    let mut inputs = vec![("text", text)];
+    inputs.extend(outputs.iter().map(|o| (o.name.as_str(), o.output.as_str())));
+    inputs
+}
+
+pub(crate) fn prompt_messages(
+    state: &AppState,
+    req: &CompletionRequest,
+) -> ActixResult<Vec<ChatMessage>> {
+    let template = state.templates.get(req.template.as_deref())?;
+    Ok(template.render(&req.question, &req.variables)?)
+}
+
+/// Starts a streamed chat for `req` on `provider` and relays it as SSE.
+///
+/// Pipeline steps before the last one run to completion first; only the
+/// final step is streamed.
+async fn stream_with(
+    state: &AppState,
+    principal: &Principal,
+    provider: Arc<dyn LlmProvider>,
+    req: &CompletionRequest,
+) -> ActixResult<HttpResponse> {
+    let options = state.check_params(principal, provider.as_ref(), &req.params)?;
+    let prepared = completion_request(state, principal, &provider, options, req).await?;
+    // Errors before the first byte still surface as a normal JSON error.
+    let answered = ask_stream(state, principal, provider, prepared.request).await?;
+
+    let mut failed_attempts = prepared.failed_attempts;
+    failed_attempts.extend(answered.failed_attempts);
+    let (state, principal) = (state.clone(), principal.clone());
+    let (provider, model, earlier_usage) = (answered.provider, answered.model, prepared.usage);
+    Ok(sse::stream_response(
+        provider,
+        answered.value,
+        move |usage| {
+            let usage = state.account(&principal, provider, &model, usage);
+            let mut done = serde_json::Map::new();
+            let usage = UsageReport::total(earlier_usage.into_iter().chain([usage]));
+            done.insert("usage".to_string(), json!(usage));
+            done.insert("provider".to_string(), json!(provider));
+            done.insert("model".to_string(), json!(model));
+            if !failed_attempts.is_empty() {
+                done.insert("failed_attempts".to_string(), json!(failed_attempts));
+            }
+            done
+        },
+    ))
+}
+
+async fn completion(
+    http: HttpRequest,
+    req: web::Json<CompletionRequest>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let directives = CacheDirectives::from_headers(http.headers());
+    answer_with(
+        &state,
+        &principal,
+        state.provider("openrouter")?,
+        &req,
+        directives,
+    )
+    .await
+}
+
+/// Same as `completion`, but relays the answer token by token as SSE.
+async fn completion_stream(
+    req: web::Json<CompletionRequest>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    stream_with(&state, &principal, state.provider("openrouter")?, &req).await
+}
+
+/// Summarizes text of any length by map-reducing over token-bounded chunks.
+async fn summarize(
+    req: web::Json<SummarizeRequest>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let provider = state.provider("openrouter")?;
+    let summarizer = state
+        .summarizer
+        .as_ref()
+        .ok_or(SummarizeError::NotConfigured)?;
+    let options = state.check_params(&principal, provider.as_ref(), &req.params)?;
+    let summary = summarizer
+        .summarize(&req.text, req.include_chunks, &options)
+        .await?;
+    let model = summary
+        .model
+        .or(options.model)
+        .unwrap_or_else(|| provider.default_model().to_string());
+    let usage = state.account(&principal, provider.name(), &model, summary.usage);
+    Ok(HttpResponse::Ok().json(SummarizeResponse {
+        summary: summary.summary,
+        chunk_count: summary.chunk_count,
+        chunk_summaries: summary.chunk_summaries,
+        usage,
+    }))
+}
+
+async fn list_templates(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "default": state.templates.default_name(),
+        "templates": state.templates.infos(),
+        "pipelines": state.templates.pipeline_infos(),
+    }))
+}
+
+/// Every provider, with its default model or why it is disabled.
+async fn list_providers(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "providers": state.providers.infos() }))
+}
+
+async fn greeter_with_name(nameparam: web::Path<String>) -> HttpResponse {
+    let name = nameparam.as_str();
+    HttpResponse::Ok().body(format!("Hello, {}!", name))
+}
+
+async fn greeter_default() -> HttpResponse {
+    HttpResponse::Ok().body("Hello, world!")
+}
+
+async fn groqlive(
+    http: HttpRequest,
+    req: web::Json<CompletionRequest>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let directives = CacheDirectives::from_headers(http.headers());
+    answer_with(
+        &state,
+        &principal,
+        state.provider("groq")?,
+        &req,
+        directives,
+    )
+    .await
+}
+
+/// Streams the Groq answer as SSE, same event format as `/completion/stream`.
+async fn groqlive_stream(
+    req: web::Json<CompletionRequest>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    stream_with(&state, &principal, state.provider("groq")?, &req).await
+}
+
+#[derive(Deserialize)]
+struct ListConversationsQuery {
+    #[serde(default = "default_list_limit")]
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+}
+
+fn default_list_limit() -> u32 {
+    50
+}
+
+fn conversation_not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(json!({ "error": format!("Conversation {} not found", id) }))
+}
+
+/// Runs a blocking storage call on the actix thread pool.
+async fn with_storage<T, F>(state: &AppState, f: F) -> ActixResult<T>
+where
+    F: FnOnce(&Storage) -> rusqlite::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let storage = state.storage.clone();
+    web::block(move || f(&storage))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)
+}
+
+/// Starts a conversation for `principal` on `provider` and stores it,
+/// returning its id.
+pub(crate) async fn start_conversation(
+    state: &AppState,
+    principal: &Principal,
+    provider: &str,
+) -> ActixResult<String> {
+    // Fail early rather than on the first message.
+    state.provider(provider)?;
+
+    let conversation_id = state.conversations.create(&principal.label, provider);
+    let (id, owner, name) = (
+        conversation_id.clone(),
+        principal.label.clone(),
+        provider.to_string(),
+    );
+    with_storage(state, move |s| s.create_conversation(&id, &owner, &name)).await?;
+    Ok(conversation_id)
+}
+
+/// The conversation `id` if `principal` started it, reloaded from disk when
+/// it was evicted from memory or is from before a restart.
+pub(crate) async fn load_conversation(
+    state: &AppState,
+    principal: &Principal,
+    id: &str,
+) -> ActixResult<Option<Conversation>> {
+    if let Some(conversation) = state.conversations.get(id) {
+        return Ok(Some(conversation).filter(|c| c.owner == principal.label));
+    }
+    let (owner, lookup) = (principal.label.clone(), id.to_string());
+    let record = with_storage(state, move |s| s.get_conversation(&owner, &lookup)).await?;
+    Ok(record.map(|record| {
+        let conversation = record.to_conversation();
+        state.conversations.restore(conversation.clone());
+        conversation
+    }))
+}
+
+/// Stores a finished turn on disk and in memory; `false` if the conversation
+/// was deleted meanwhile.
+///
+/// A conversation evicted from memory while its answer was on the way is
+/// reloaded from disk, turn included.
+pub(crate) async fn record_turn(
+    state: &AppState,
+    principal: &Principal,
+    id: &str,
+    turn: Turn,
+    meta: AnswerMeta,
+) -> ActixResult<bool> {
+    let (conversation_id, on_disk) = (id.to_string(), turn.clone());
+    let stored = with_storage(state, move |s| {
+        s.append_turn(&conversation_id, &on_disk, &meta)
+    })
+    .await?;
+    if !stored || state.conversations.append(id, turn) {
+        return Ok(stored);
+    }
+    let (owner, lookup) = (principal.label.clone(), id.to_string());
+    let record = with_storage(state, move |s| s.get_conversation(&owner, &lookup)).await?;
+    if let Some(record) = record {
+        state.conversations.restore(record.to_conversation());
+    }
+    Ok(true)
+}
+
+async fn create_conversation(
+    req: Option<web::Json<CreateConversationRequest>>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let provider = req
+        .map(|r| r.into_inner().provider)
+        .unwrap_or_else(default_conversation_provider);
+    let conversation_id = start_conversation(&state, &principal, &provider).await?;
+    Ok(HttpResponse::Created().json(CreateConversationResponse {
+        conversation_id,
+        provider,
+    }))
+}
+
+/// Appends a question to a conversation, replaying earlier turns to the model.
+async fn append_message(
+    path: web::Path<String>,
+    req: web::Json<CompletionRequest>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let id = path.into_inner();
+    if req.mode.is_some() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Pipelines are not supported in conversations",
+        ));
+    }
+    let Some(conversation) = load_conversation(&state, &principal, &id).await? else {
+        return Ok(conversation_not_found(&id));
+    };
+
+    // History goes in verbatim; only the new question is wrapped in the prompt template.
+    let messages = templates::with_history(prompt_messages(&state, &req)?, conversation.history());
+
+    let provider = state.provider(&conversation.provider)?;
+    let options = state.check_params(&principal, provider.as_ref(), &req.params)?;
+    let started = Instant::now();
+    let answered = ask(
+        &state,
+        &principal,
+        provider,
+        ChatRequest::new(messages).with_options(options),
+    )
+    .await?;
+    let usage = state.account(
+        &principal,
+        answered.provider,
+        &answered.model,
+        answered.value.usage,
+    );
+
+    let turn = Turn {
+        question: req.into_inner().question,
+        answer: answered.value.content.clone(),
+    };
+    // Record who actually answered; the conversation keeps its own provider.
+    let meta = AnswerMeta {
+        provider: answered.provider.to_string(),
+        model: answered.model.clone(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        usage: answered.value.usage,
+    };
+
+    if !record_turn(&state, &principal, &id, turn, meta).await? {
+        return Ok(conversation_not_found(&id));
+    }
+
+    Ok(HttpResponse::Ok().json(CompletionResponse {
+        answer: answered.value.content,
+        provider: answered.provider.to_string(),
+        model: answered.model,
+        cached: false,
+        usage,
+        failed_attempts: answered.failed_attempts,
+        steps: None,
+    }))
+}
+
+/// The caller's conversations; other keys' conversations are never listed.
+async fn list_conversations(
+    query: web::Query<ListConversationsQuery>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let ListConversationsQuery { limit, offset } = query.into_inner();
+    let conversations = with_storage(&state, move |s| {
+        s.list_conversations(&principal.label, limit.min(500), offset)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({ "conversations": conversations })))
+}
+
+async fn get_conversation(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let id = path.into_inner();
+    let lookup = id.clone();
+    match with_storage(&state, move |s| {
+        s.get_conversation(&principal.label, &lookup)
+    })
+    .await?
+    {
+        Some(record) => Ok(HttpResponse::Ok().json(record)),
+        None => Ok(conversation_not_found(&id)),
+    }
+}
+
+async fn delete_conversation(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let id = path.into_inner();
+    let lookup = id.clone();
+    if with_storage(&state, move |s| {
+        s.delete_conversation(&principal.label, &lookup)
+    })
+    .await?
+    {
+        state.conversations.remove(&id);
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(conversation_not_found(&id))
+    }
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    /// First day, `YYYY-MM-DD` (UTC).
+    from: Option<String>,
+    /// Last day, `YYYY-MM-DD` (UTC).
+    to: Option<String>,
+    api_key: Option<String>,
+}
+
+/// Token and cost totals per API key and day. Needs an API key allowed to call
+/// /admin; keys that are not admin keys only see their own totals.
+async fn usage_totals(
+    query: web::Query<UsageQuery>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    if !principal.is_authenticated() {
+        return Ok(HttpResponse::Forbidden().json(json!({
+            "error": "Admin endpoints are only available when API keys are configured"
+        })));
+    }
+    let UsageQuery { from, to, api_key } = query.into_inner();
+    let api_key = if principal.is_admin() {
+        api_key
+    } else {
+        Some(principal.label)
+    };
+    let filter = UsageFilter { from, to, api_key };
+    let totals = with_storage(&state, move |s| s.usage_totals(&filter)).await?;
+    Ok(HttpResponse::Ok().json(json!({ "usage": totals })))
+}
+
+/// Liveness: the process is up and answering.
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Readiness: the static site, the database and every provider; 503 if the
+/// site or the database failed, or no enabled provider answered.
+async fn readyz(state: web::Data<AppState>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "static".to_string(),
+        health::check_static_dir(&state.config.server.static_dir),
+    );
+
+    let storage = state.storage.clone();
+    let storage_check = match web::block(move || storage.ping()).await {
+        Ok(Ok(())) => Check::ok("database readable"),
+        Ok(Err(e)) => Check::failed(e.to_string()),
+        Err(e) => Check::failed(e.to_string()),
+    };
+    checks.insert("storage".to_string(), storage_check);
+
+    let provider_probes = &state.probes;
+    let probes = state.providers.iter().map(|provider| async move {
+        let check = provider_probes.check(provider.as_ref()).await;
+        (provider.name(), check)
+    });
+    let mut provider_checks = futures_util::future::join_all(probes).await;
+    // Startup rejects invalid settings, so what is left to check is that at
+    // least one provider can be used.
+    let config = if provider_checks.is_empty() {
+        Check::failed("no provider has an API key")
+    } else {
+        Check::ok(format!(
+            "{} template(s), {} provider(s) enabled",
+            state.templates.infos().len(),
+            provider_checks.len()
+        ))
+    };
+    checks.insert("config".to_string(), config);
+    for (name, reason) in state.providers.disabled() {
+        provider_checks.push((name, Check::disabled(reason)));
+    }
+
+    let readiness = Readiness::new(checks, provider_checks);
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+/// Prometheus metrics in the text exposition format.
+async fn prometheus_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics().render())
+}
+
+/// Mounts the API, the greeter and the static site on `cfg`.
+///
+/// API routes require a key when any is configured; the key check wraps the
+/// rate limit so key holders are limited per key. A route and its /stream
+/// variant share one limit per client.
+pub fn configure(cfg: &mut web::ServiceConfig, state: &AppState) {
+    cfg.app_data(web::Data::new(state.clone()))
+        .service(
+            web::resource("/completion")
+                .wrap(state.rate_limits.layer("/completion"))
+                .wrap(state.api_keys.require())
+                .route(web::post().to(completion)),
+        )
+        .service(
+            web::resource("/completion/stream")
+                .wrap(state.rate_limits.layer("/completion"))
+                .wrap(state.api_keys.require())
+                .route(web::post().to(completion_stream)),
+        )
+        .service(
+            web::resource("/groqlive")
+                .wrap(state.rate_limits.layer("/groqlive"))
+                .wrap(state.api_keys.require())
+                .route(web::post().to(groqlive)),
+        )
+        .service(
+            web::resource("/groqlive/stream")
+                .wrap(state.rate_limits.layer("/groqlive"))
+                .wrap(state.api_keys.require())
+                .route(web::post().to(groqlive_stream)),
+        )
+        .service(
+            web::resource("/summarize")
+                .wrap(state.rate_limits.layer("/summarize"))
+                .wrap(state.api_keys.require())
+                .route(web::post().to(summarize)),
+        )
+        .route("/templates", web::get().to(list_templates))
+        .route("/providers", web::get().to(list_providers))
+        .route("/metrics", web::get().to(prometheus_metrics))
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+        .service(
+            web::scope("/conversations")
+                .wrap(state.api_keys.require())
+                .route("", web::post().to(create_conversation))
+                .route("", web::get().to(list_conversations))
+                .route("/{id}", web::get().to(get_conversation))
+                .route("/{id}", web::delete().to(delete_conversation))
+                .service(
+                    web::resource("/{id}/messages")
+                        .wrap(state.rate_limits.layer("/conversations/{id}/messages"))
+                        .route(web::post().to(append_message)),
+                ),
+        )
+        .service(
+            web::scope("/admin")
+                .wrap(state.api_keys.require())
+                .route("/usage", web::get().to(usage_totals)),
+        )
+        // OpenAI-compatible API, see openai.rs.
+        .service(
+            web::scope("/v1")
+                .wrap(state.api_keys.require())
+                .wrap(openai::error_handlers())
+                .service(
+                    web::resource("/chat/completions")
+                        .wrap(state.rate_limits.layer("/v1/chat/completions"))
+                        .route(web::post().to(openai::chat_completions)),
+                )
+                .route("/models", web::get().to(openai::models)),
+        )
+        // Register /name route BEFORE static files to avoid route conflicts
+        .service(
+            web::scope("/name")
+                .route("/{nameparam}", web::get().to(greeter_with_name))
+                .route("", web::get().to(greeter_default)),
+        )
+        // Serve static files (images, etc.) from /static path
+        .service(Files::new("/static", &state.config.server.static_dir))
+        // Serve index.html and other static files from root
+        .service(Files::new("/", &state.config.server.static_dir).index_file("index.html"));
+}
@@ -0,0 +1,356 @@
+//! Optional bearer-token authentication for the API routes.
+//!
+//! Keys are configured by their SHA-256 hash, never in plain text, either in
+//! a TOML file:
+//!
+//! ```toml
+//! [[keys]]
+//! label = "website"
+//! sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! routes = ["/completion", "/conversations"]
+//! models = ["meta-llama/llama-3.2-3b-instruct"]
+//! admin = false
+//! ```
+//!
+//! or as `label=sha256` pairs in an environment variable, which may use every
+//! route and model. Only file keys with `admin = true` see everyone's data on
+//! the admin routes. A route entry also covers everything below it, so
+//! `/completion` allows `/completion/stream`. When no key is configured at all
+//! authentication is off and every request runs as `Principal::anonymous`.
+//!
+//! `RequireApiKey` checks the `Authorization: Bearer <key>` header and stores
+//! the matching `Principal` in the request extensions, where handlers pick it
+//! up as an extractor.
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::{
+    http::StatusCode, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::path::Path;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// The client a request was authenticated as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    /// The key's label; `anonymous` when authentication is off.
+    pub label: String,
+    /// Route prefixes the key may call; empty allows every route.
+    routes: Vec<String>,
+    /// Models the key may request; empty allows every model.
+    models: Vec<String>,
+    admin: bool,
+    authenticated: bool,
+}
+
+impl Principal {
+    /// Everyone, when no API keys are configured.
+    pub fn anonymous() -> Self {
+        Self {
+            label: "anonymous".to_string(),
+            routes: Vec::new(),
+            models: Vec::new(),
+            admin: false,
+            authenticated: false,
+        }
+    }
+
+    /// Whoever runs the command line client; usage is recorded as `cli`.
+    pub fn terminal() -> Self {
+        Self {
+            label: "cli".to_string(),
+            ..Self::anonymous()
+        }
+    }
+
+    /// Whether the request presented a valid API key.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Whether the key was explicitly made an admin key.
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    pub fn allows_route(&self, path: &str) -> bool {
+        self.routes.is_empty()
+            || self.routes.iter().any(|route| {
+                path == route
+                    || path
+                        .strip_prefix(route.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    pub fn check_model(&self, model: &str) -> Result<(), AuthError> {
+        if self.models.is_empty() || self.models.iter().any(|m| m == "*" || m == model) {
+            Ok(())
+        } else {
+            Err(AuthError::ModelNotAllowed {
+                label: self.label.clone(),
+                model: model.to_string(),
+            })
+        }
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(req
+            .extensions()
+            .get::<Principal>()
+            .cloned()
+            .unwrap_or_else(Principal::anonymous)))
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingKey,
+    InvalidKey,
+    RouteNotAllowed { label: String, route: String },
+    ModelNotAllowed { label: String, model: String },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingKey => write!(f, "Missing API key, send Authorization: Bearer <key>"),
+            AuthError::InvalidKey => write!(f, "Invalid API key"),
+            AuthError::RouteNotAllowed { label, route } => {
+                write!(f, "API key '{}' may not call {}", label, route)
+            }
+            AuthError::ModelNotAllowed { label, model } => {
+                write!(f, "API key '{}' may not use model '{}'", label, model)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => StatusCode::UNAUTHORIZED,
+            AuthError::RouteNotAllowed { .. } | AuthError::ModelNotAllowed { .. } => {
+                StatusCode::FORBIDDEN
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header((WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(json!({ "error": self.to_string() }))
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyFile {
+    #[serde(default)]
+    keys: Vec<KeyEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    label: String,
+    sha256: String,
+    #[serde(default)]
+    routes: Vec<String>,
+    #[serde(default)]
+    models: Vec<String>,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// The configured keys, looked up by hash.
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeys {
+    by_hash: HashMap<String, Principal>,
+}
+
+impl ApiKeys {
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    /// Authentication is off when no key is configured.
+    pub fn is_empty(&self) -> bool {
+        self.by_hash.is_empty()
+    }
+
+    /// Adds the keys of a TOML key file.
+    pub fn load(self, path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let file: KeyFile = toml::from_str(&contents)
+            .map_err(|e| format!("Invalid API key file {}: {}", path.display(), e))?;
+        file.keys.into_iter().try_fold(self, |keys, entry| {
+            keys.insert(
+                &entry.label,
+                &entry.sha256,
+                entry.routes,
+                entry.models,
+                entry.admin,
+            )
+        })
+    }
+
+    /// Adds keys from a comma-separated list of `label=sha256` pairs.
+    pub fn parse(self, list: &str) -> Result<Self, String> {
+        list.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .try_fold(self, |keys, entry| {
+                let (label, hash) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid API key '{}', expected label=sha256", entry))?;
+                keys.insert(label.trim(), hash.trim(), Vec::new(), Vec::new(), false)
+            })
+    }
+
+    fn insert(
+        mut self,
+        label: &str,
+        hash: &str,
+        routes: Vec<String>,
+        models: Vec<String>,
+        admin: bool,
+    ) -> Result<Self, String> {
+        let hash = hash.to_ascii_lowercase();
+        if label.is_empty() {
+            return Err("API key labels must not be empty".to_string());
+        }
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!(
+                "API key '{}' needs a SHA-256 hash of 64 hex digits",
+                label
+            ));
+        }
+        if self.by_hash.values().any(|p| p.label == label) {
+            return Err(format!("API key label '{}' is used twice", label));
+        }
+        let principal = Principal {
+            label: label.to_string(),
+            routes,
+            models,
+            admin,
+            authenticated: true,
+        };
+        if self.by_hash.insert(hash, principal).is_some() {
+            return Err(format!("API key '{}' duplicates another key", label));
+        }
+        Ok(self)
+    }
+
+    /// The principal for a presented plain-text `key`.
+    pub fn authenticate(&self, key: &str) -> Option<&Principal> {
+        self.by_hash.get(&hash_key(key))
+    }
+
+    /// Middleware requiring a valid key on every route it wraps.
+    pub fn require(self: &Arc<Self>) -> RequireApiKey {
+        RequireApiKey { keys: self.clone() }
+    }
+}
+
+/// Hex-encoded SHA-256 of `key`, the form keys are configured in.
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// The principal for `req`, or why it has none.
+fn principal_for(keys: &ApiKeys, req: &ServiceRequest) -> Result<Principal, AuthError> {
+    if keys.is_empty() {
+        return Ok(Principal::anonymous());
+    }
+    let key = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .ok_or(AuthError::MissingKey)?;
+    let principal = keys.authenticate(key).ok_or(AuthError::InvalidKey)?;
+    if !principal.allows_route(req.path()) {
+        return Err(AuthError::RouteNotAllowed {
+            label: principal.label.clone(),
+            route: req.path().to_string(),
+        });
+    }
+    Ok(principal.clone())
+}
+
+/// Middleware created by `ApiKeys::require`.
+#[derive(Clone)]
+pub struct RequireApiKey {
+    keys: Arc<ApiKeys>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireApiKey
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireApiKeyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireApiKeyMiddleware {
+            service: Rc::new(service),
+            keys: self.keys.clone(),
+        }))
+    }
+}
+
+pub struct RequireApiKeyMiddleware<S> {
+    service: Rc<S>,
+    keys: Arc<ApiKeys>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireApiKeyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        match principal_for(&self.keys, &req) {
+            Ok(principal) => {
+                req.extensions_mut().insert(principal);
+                let service = self.service.clone();
+                Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+            }
+            Err(error) => {
+                let response = error.error_response();
+                Box::pin(async move { Ok(req.into_response(response).map_into_right_body()) })
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Cache for answers to repeated questions.
+//!
+//! The key is a SHA-256 over everything that shapes an answer: provider,
+//! model, the normalized question (trimmed, lowercased, whitespace collapsed),
+//! template or pipeline, template variables and sampling options. Entries
+//! live in an in-memory LRU or, to survive restarts, as one JSON file per key
+//! in a directory. Either way they expire after the TTL. Clients skip the
+//! cache with `Cache-Control: no-cache` (or `no-store` to not fill it either).
+
+use crate::providers::ChatOptions;
+use actix_web::http::header::{HeaderMap, CACHE_CONTROL};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// What is cached of an answer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedAnswer {
+    pub answer: String,
+    pub provider: String,
+    pub model: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub value: CachedAnswer,
+    /// Seconds since the Unix epoch.
+    pub stored_at: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Everything a cache key is derived from.
+#[derive(Serialize)]
+pub struct CacheKey<'a> {
+    pub provider: &'a str,
+    pub model: &'a str,
+    pub question: &'a str,
+    pub template: Option<&'a str>,
+    pub mode: Option<&'a str>,
+    pub variables: BTreeMap<&'a str, &'a str>,
+    pub options: &'a ChatOptions,
+}
+
+impl CacheKey<'_> {
+    /// Hex digest of the key with the question normalized.
+    pub fn digest(&self) -> String {
+        let question = self
+            .question
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let key = CacheKey {
+            question: &question,
+            variables: self.variables.clone(),
+            ..*self
+        };
+        let json = serde_json::to_vec(&key).expect("cache keys serialize");
+        format!("{:x}", Sha256::digest(json))
+    }
+}
+
+/// Where cached answers are kept.
+pub trait CacheBackend: Send + Sync {
+    fn get(&self, key: &str) -> Option<CacheEntry>;
+    fn put(&self, key: &str, entry: CacheEntry);
+    fn remove(&self, key: &str);
+    /// Drops every entry stored before `cutoff` (Unix seconds).
+    fn evict_before(&self, cutoff: u64);
+}
+
+/// Keeps up to `capacity` entries, dropping the least recently used.
+pub struct MemoryBackend {
+    capacity: usize,
+    state: Mutex<LruState>,
+}
+
+#[derive(Default)]
+struct LruState {
+    /// Entry and the tick of its last use.
+    entries: HashMap<String, (CacheEntry, u64)>,
+    /// Keys by last use, oldest first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl LruState {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((_, used)) = self.entries.get_mut(key) {
+            self.order.remove(used);
+            *used = tick;
+            self.order.insert(tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+}
+
+impl MemoryBackend {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            state: Mutex::new(LruState::default()),
+        }
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let mut state = self.state.lock().unwrap();
+        state.touch(key);
+        state.entries.get(key).map(|(entry, _)| entry.clone())
+    }
+
+    fn put(&self, key: &str, entry: CacheEntry) {
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+        while state.entries.len() >= self.capacity {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.entries.insert(key.to_string(), (entry, tick));
+        state.order.insert(tick, key.to_string());
+    }
+
+    fn remove(&self, key: &str) {
+        self.state.lock().unwrap().remove(key);
+    }
+
+    fn evict_before(&self, cutoff: u64) {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, (entry, _))| entry.stored_at < cutoff)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            state.remove(&key);
+        }
+    }
+}
+
+/// One `<key>.json` file per entry in `dir`.
+pub struct DiskBackend {
+    dir: PathBuf,
+}
+
+impl DiskBackend {
+    pub fn open(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+impl CacheBackend for DiskBackend {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let contents = std::fs::read(self.path(key)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    fn put(&self, key: &str, entry: CacheEntry) {
+        // Write then rename, so readers never see half a file.
+        let path = self.path(key);
+        let tmp = self
+            .dir
+            .join(format!("{}.{:x}.tmp", key, fastrand::u64(..)));
+        let written = serde_json::to_vec(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&tmp, json))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = written {
+            tracing::warn!(path = %path.display(), error = %e, "failed to write cache entry");
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = std::fs::remove_file(self.path(key));
+    }
+
+    fn evict_before(&self, cutoff: u64) {
+        let Ok(files) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let expired = std::fs::read(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_slice::<CacheEntry>(&contents).ok())
+                .is_none_or(|entry| entry.stored_at < cutoff);
+            if expired {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+/// What the client's `Cache-Control` header allows for one request.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheDirectives {
+    /// `no-cache` asks for a fresh answer.
+    pub lookup: bool,
+    /// `no-store` also keeps the answer out of the cache.
+    pub store: bool,
+}
+
+impl CacheDirectives {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut directives = Self {
+            lookup: true,
+            store: true,
+        };
+        let values = headers
+            .get_all(CACHE_CONTROL)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for value in values {
+            match value.trim().to_ascii_lowercase().as_str() {
+                "no-cache" => directives.lookup = false,
+                "no-store" => {
+                    directives.lookup = false;
+                    directives.store = false;
+                }
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+/// A cache backend plus the time entries stay valid.
+pub struct ResponseCache {
+    ttl: Duration,
+    backend: Box<dyn CacheBackend>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, backend: impl CacheBackend + 'static) -> Self {
+        Self {
+            ttl,
+            backend: Box::new(backend),
+        }
+    }
+
+    fn cutoff(&self) -> u64 {
+        now_secs().saturating_sub(self.ttl.as_secs())
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedAnswer> {
+        let entry = self.backend.get(key)?;
+        if entry.stored_at < self.cutoff() {
+            self.backend.remove(key);
+            return None;
+        }
+        Some(entry.value)
+    }
+
+    pub fn put(&self, key: &str, value: CachedAnswer) {
+        self.backend.put(
+            key,
+            CacheEntry {
+                value,
+                stored_at: now_secs(),
+            },
+        );
+    }
+
+    pub fn evict_expired(&self) {
+        self.backend.evict_before(self.cutoff());
+    }
+}
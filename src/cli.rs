@@ -0,0 +1,428 @@
+//! Command line interface.
+//!
+//! Besides `serve`, the binary answers questions in the terminal: `ask` for
+//! one question and `chat` for a conversation. Both run in-process on the
+//! same providers, prompt templates, conversation store and usage records
+//! as the HTTP handlers.
+
+use crate::app::{
+    ask, ask_stream, completion_request, load_conversation, prompt_messages, record_turn,
+    start_conversation, AppState, CompletionRequest,
+};
+use crate::auth::Principal;
+use crate::conversations::Turn;
+use crate::params::RequestParams;
+use crate::providers::{ChatRequest, ChatStream, StreamEvent, Usage};
+use crate::storage::AnswerMeta;
+use crate::templates;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read, Write};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+pub const USAGE: &str = "\
+Usage: rustybot [COMMAND] [OPTIONS]
+
+Commands:
+  serve               Run the HTTP server (the default)
+  ask [QUESTION]      Answer one question; reads it from stdin when omitted
+  chat                Start an interactive conversation
+  help                Show this message
+
+Options for ask and chat:
+  --provider NAME     openrouter (default) or groq
+  --template NAME     Prompt template to wrap questions in
+  --var KEY=VALUE     Template variable, may be repeated
+  --model ID          Model override, checked like on the API
+  --temperature T     Sampling temperature
+  --max-tokens N      Upper bound on answer tokens
+
+Options for ask:
+  --mode NAME         Run a pipeline instead of a single template
+  --no-stream         Print the answer once it is complete
+
+Options for chat:
+  --resume ID         Continue a stored conversation
+
+Settings come from rustybot.toml and the environment, as for the server.
+";
+
+const CHAT_HELP: &str = "\
+Commands:
+  /history   Show the conversation so far
+  /new       Start a new conversation
+  /quit      Leave (or Ctrl-D)";
+
+/// What the binary was asked to do.
+#[derive(Debug)]
+pub enum Command {
+    Serve,
+    Ask(AskArgs),
+    Chat(ChatArgs),
+    Help,
+}
+
+/// How questions are put to the provider, shared by `ask` and `chat`.
+#[derive(Clone, Debug)]
+pub struct PromptArgs {
+    pub provider: String,
+    pub template: Option<String>,
+    pub variables: HashMap<String, String>,
+    pub params: RequestParams,
+}
+
+impl Default for PromptArgs {
+    fn default() -> Self {
+        Self {
+            provider: "openrouter".to_string(),
+            template: None,
+            variables: HashMap::new(),
+            params: RequestParams::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AskArgs {
+    /// Read from stdin when omitted.
+    pub question: Option<String>,
+    pub prompt: PromptArgs,
+    pub mode: Option<String>,
+    pub no_stream: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ChatArgs {
+    pub prompt: PromptArgs,
+    /// Stored conversation to continue; its provider wins over `--provider`.
+    pub resume: Option<String>,
+}
+
+impl Command {
+    /// Parses the arguments after the program name. No arguments means
+    /// `serve`, so existing deployments keep starting the server.
+    pub fn parse<I>(args: I) -> Result<Command, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let Some(command) = args.next() else {
+            return Ok(Command::Serve);
+        };
+        match command.as_str() {
+            "serve" => match args.next() {
+                None => Ok(Command::Serve),
+                Some(arg) => Err(format!("serve takes no arguments, got '{}'", arg)),
+            },
+            "ask" => {
+                let mut ask_args = AskArgs::default();
+                let mut words = Vec::new();
+                parse_options(args, |flag, value| {
+                    match flag {
+                        None => words.push(value()?),
+                        Some("--mode") => ask_args.mode = Some(value()?),
+                        Some("--no-stream") => ask_args.no_stream = true,
+                        Some(flag) => return prompt_option(&mut ask_args.prompt, flag, value),
+                    }
+                    Ok(())
+                })?;
+                if !words.is_empty() {
+                    ask_args.question = Some(words.join(" "));
+                }
+                Ok(Command::Ask(ask_args))
+            }
+            "chat" => {
+                let mut chat = ChatArgs::default();
+                parse_options(args, |flag, value| match flag {
+                    None => Err(format!("chat takes no question, got '{}'", value()?)),
+                    Some("--resume") => {
+                        chat.resume = Some(value()?);
+                        Ok(())
+                    }
+                    Some(flag) => prompt_option(&mut chat.prompt, flag, value),
+                })?;
+                Ok(Command::Chat(chat))
+            }
+            "help" | "-h" | "--help" => Ok(Command::Help),
+            other => Err(format!("unknown command '{}'\n\n{}", other, USAGE)),
+        }
+    }
+}
+
+/// Calls `handle` with each `--flag` (`None` for positional arguments) and a
+/// function taking its value, given as `--flag value` or `--flag=value`.
+fn parse_options<F>(mut args: impl Iterator<Item = String>, mut handle: F) -> Result<(), String>
+where
+    F: FnMut(Option<&str>, &mut dyn FnMut() -> Result<String, String>) -> Result<(), String>,
+{
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            let mut positional = Some(arg);
+            handle(None, &mut || Ok(positional.take().unwrap_or_default()))?;
+            continue;
+        }
+        let (flag, mut inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        handle(Some(flag.as_str()), &mut value)?;
+    }
+    Ok(())
+}
+
+/// Applies one of the options `ask` and `chat` share.
+fn prompt_option(
+    prompt: &mut PromptArgs,
+    flag: &str,
+    value: &mut dyn FnMut() -> Result<String, String>,
+) -> Result<(), String> {
+    match flag {
+        "--provider" => prompt.provider = value()?,
+        "--template" => prompt.template = Some(value()?),
+        "--var" => {
+            let var = value()?;
+            let (key, val) = var
+                .split_once('=')
+                .ok_or_else(|| format!("--var expects KEY=VALUE, got '{}'", var))?;
+            prompt
+                .variables
+                .insert(key.trim().to_string(), val.to_string());
+        }
+        "--model" => prompt.params.model = Some(value()?),
+        "--temperature" => prompt.params.temperature = Some(number(flag, value()?)?),
+        "--max-tokens" => prompt.params.max_tokens = Some(number(flag, value()?)?),
+        other => return Err(format!("unknown option '{}'\n\n{}", other, USAGE)),
+    }
+    Ok(())
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} expects a number, got '{}'", flag, value))
+}
+
+impl PromptArgs {
+    fn request(&self, question: String, mode: Option<String>) -> CompletionRequest {
+        CompletionRequest {
+            question,
+            template: self.template.clone(),
+            variables: self.variables.clone(),
+            mode,
+            debug: false,
+            params: self.params.clone(),
+        }
+    }
+}
+
+/// Answers one question on stdout.
+pub async fn run_ask(state: &AppState, args: AskArgs) -> Result<(), String> {
+    let question = match args.question {
+        Some(question) => question,
+        None if std::io::stdin().is_terminal() => {
+            return Err(format!("ask needs a question\n\n{}", USAGE))
+        }
+        None => {
+            let mut question = String::new();
+            std::io::stdin()
+                .read_to_string(&mut question)
+                .map_err(|e| format!("Failed to read the question from stdin: {}", e))?;
+            question
+        }
+    };
+    if question.trim().is_empty() {
+        return Err("The question is empty".to_string());
+    }
+
+    let principal = Principal::terminal();
+    let req = args.prompt.request(question, args.mode);
+    let provider = state.provider(&args.prompt.provider).map_err(message)?;
+    let options = state
+        .check_params(&principal, provider.as_ref(), &req.params)
+        .map_err(message)?;
+    let prepared = completion_request(state, &principal, &provider, options, &req)
+        .await
+        .map_err(message)?;
+
+    let (provider, model, usage) = if args.no_stream {
+        let answered = ask(state, &principal, provider, prepared.request)
+            .await
+            .map_err(message)?;
+        println!("{}", answered.value.content);
+        (answered.provider, answered.model, answered.value.usage)
+    } else {
+        let answered = ask_stream(state, &principal, provider, prepared.request)
+            .await
+            .map_err(message)?;
+        let (_, usage) = print_stream(answered.value).await?;
+        (answered.provider, answered.model, usage)
+    };
+    state.account_now(&principal, provider, &model, usage).await;
+    for record in prepared.recording {
+        let _ = record.await;
+    }
+    Ok(())
+}
+
+/// Runs a conversation on stdin and stdout until `/quit` or end of input.
+pub async fn run_chat(state: &AppState, args: ChatArgs) -> Result<(), String> {
+    let principal = Principal::terminal();
+    let (mut id, provider) = match &args.resume {
+        Some(id) => {
+            let conversation = load_conversation(state, &principal, id)
+                .await
+                .map_err(message)?
+                .ok_or_else(|| format!("Conversation '{}' not found", id))?;
+            (conversation.id, conversation.provider)
+        }
+        None => {
+            let provider = args.prompt.provider.clone();
+            let id = start_conversation(state, &principal, &provider)
+                .await
+                .map_err(message)?;
+            (id, provider)
+        }
+    };
+    eprintln!(
+        "Conversation {} with {}. /help lists commands.",
+        id, provider
+    );
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush().ok();
+        let Some(line) = lines
+            .next_line()
+            .await
+            .map_err(|e| format!("Failed to read stdin: {}", e))?
+        else {
+            println!();
+            break;
+        };
+        match line.trim() {
+            "" => {}
+            "/quit" | "/exit" => break,
+            "/help" => eprintln!("{}", CHAT_HELP),
+            "/history" => match load_conversation(state, &principal, &id).await {
+                Ok(Some(conversation)) => {
+                    for turn in &conversation.turns {
+                        println!("> {}\n{}\n", turn.question, turn.answer);
+                    }
+                }
+                Ok(None) => eprintln!("Conversation '{}' not found", id),
+                Err(e) => eprintln!("error: {}", e),
+            },
+            "/new" => match start_conversation(state, &principal, &provider).await {
+                Ok(new_id) => {
+                    id = new_id;
+                    eprintln!("Conversation {} with {}.", id, provider);
+                }
+                Err(e) => eprintln!("error: {}", e),
+            },
+            command if command.starts_with('/') => {
+                eprintln!("Unknown command {}\n{}", command, CHAT_HELP)
+            }
+            question => {
+                let question = question.to_string();
+                if let Err(e) = chat_turn(state, &principal, &id, &args.prompt, question).await {
+                    eprintln!("error: {}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Streams the answer to `question` with the conversation's history, then
+/// stores the turn, as `POST /conversations/{id}/messages` does.
+async fn chat_turn(
+    state: &AppState,
+    principal: &Principal,
+    id: &str,
+    prompt: &PromptArgs,
+    question: String,
+) -> Result<(), String> {
+    let conversation = load_conversation(state, principal, id)
+        .await
+        .map_err(message)?
+        .ok_or_else(|| format!("Conversation '{}' not found", id))?;
+    let req = prompt.request(question, None);
+
+    // History goes in verbatim; only the new question is wrapped in the prompt template.
+    let messages = templates::with_history(
+        prompt_messages(state, &req).map_err(message)?,
+        conversation.history(),
+    );
+    let provider = state.provider(&conversation.provider).map_err(message)?;
+    let options = state
+        .check_params(principal, provider.as_ref(), &req.params)
+        .map_err(message)?;
+    let started = Instant::now();
+    let answered = ask_stream(
+        state,
+        principal,
+        provider,
+        ChatRequest::new(messages).with_options(options),
+    )
+    .await
+    .map_err(message)?;
+    let (answer, usage) = print_stream(answered.value).await?;
+    state
+        .account_now(principal, answered.provider, &answered.model, usage)
+        .await;
+
+    let turn = Turn {
+        question: req.question,
+        answer,
+    };
+    let meta = AnswerMeta {
+        provider: answered.provider.to_string(),
+        model: answered.model,
+        latency_ms: started.elapsed().as_millis() as u64,
+        usage,
+    };
+    if !record_turn(state, principal, id, turn, meta).await.map_err(message)? {
+        return Err(format!("Conversation '{}' not found", id));
+    }
+    Ok(())
+}
+
+/// Prints the answer as it arrives and returns it in full.
+async fn print_stream(mut stream: ChatStream) -> Result<(String, Option<Usage>), String> {
+    let mut stdout = std::io::stdout();
+    let mut answer = String::new();
+    while let Some(event) = stream.next().await {
+        match event {
+            Ok(StreamEvent::Delta(content)) => {
+                print!("{}", content);
+                stdout.flush().ok();
+                answer.push_str(&content);
+            }
+            // Only requested through /v1/chat/completions.
+            Ok(StreamEvent::ToolCalls(_)) => {}
+            Ok(StreamEvent::Done { usage, .. }) => {
+                println!();
+                return Ok((answer, usage));
+            }
+            Err(e) => {
+                println!();
+                return Err(e.to_string());
+            }
+        }
+    }
+    println!();
+    Ok((answer, None))
+}
+
+fn message(error: impl std::fmt::Display) -> String {
+    error.to_string()
+}
@@ -0,0 +1,681 @@
+//! Server configuration: built-in defaults, then a TOML file, then the
+//! environment.
+//!
+//! The file is `CONFIG_PATH`, or `./rustybot.toml` when it exists:
+//!
+//! ```toml
+//! [server]
+//! port = 8080
+//!
+//! [providers.openrouter]
+//! api_key_file = "/run/secrets/openrouter"
+//! model = "meta-llama/llama-3.2-3b-instruct"
+//!
+//! [rate_limits]
+//! "/completion" = "20/60"
+//! ```
+//!
+//! Every setting can still be given by the environment variable that used to
+//! be its only source (`PORT`, `MODEL`, `RATE_LIMITS`, ...), which wins over
+//! the file. Provider API keys can also be read from files such as Docker or
+//! Kubernetes secrets, with `api_key_file` or `OPENROUTER_API_KEY_FILE` and
+//! `GROQ_API_KEY_FILE`.
+//!
+//! `Config::load` checks everything that does not need the network, so a
+//! typo stops the server at startup with the setting's name in the error.
+
+use crate::cors::{self, CorsConfig};
+use crate::fallback::FallbackTarget;
+use crate::providers::RetryPolicy;
+use crate::rate_limit::{RateLimits, TrustedProxies, LIMITED_ROUTES};
+use crate::summarize::MIN_PROMPT_TOKENS;
+use crate::telemetry::LogFormat;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Providers the server knows, as named in settings.
+const PROVIDERS: [&str; 2] = ["openrouter", "groq"];
+
+/// A value that is never printed, such as an API key.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub providers: ProvidersConfig,
+    pub upstream: UpstreamConfig,
+    pub mock: MockConfig,
+    pub params: ParamsConfig,
+    pub templates: FileSetting,
+    pub prices: FileSetting,
+    pub conversations: ConversationsConfig,
+    pub summarize: SummarizeConfig,
+    pub cache: CacheConfig,
+    pub auth: AuthConfig,
+    pub cors: CorsConfig,
+    /// Requests per client as `requests/seconds`, by route.
+    pub rate_limits: BTreeMap<String, String>,
+    pub readiness: ReadinessConfig,
+    /// The file the configuration was read from, if any.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig::default(),
+            database: DatabaseConfig::default(),
+            providers: ProvidersConfig::default(),
+            upstream: UpstreamConfig::default(),
+            mock: MockConfig::default(),
+            params: ParamsConfig::default(),
+            templates: FileSetting::default(),
+            prices: FileSetting::default(),
+            conversations: ConversationsConfig::default(),
+            summarize: SummarizeConfig::default(),
+            cache: CacheConfig::default(),
+            auth: AuthConfig::default(),
+            cors: CorsConfig::default(),
+            rate_limits: LIMITED_ROUTES
+                .map(|route| (route.to_string(), "20/60".to_string()))
+                .into(),
+            readiness: ReadinessConfig::default(),
+            source: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Served at /static and as the site root.
+    pub static_dir: PathBuf,
+    pub log_format: LogFormat,
+    /// Reverse proxies (addresses or CIDR ranges) whose `X-Forwarded-For`
+    /// names the client for rate limiting. Empty trusts no one.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            static_dir: PathBuf::from("./static"),
+            log_format: LogFormat::Text,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("rustybot.db"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvidersConfig {
+    pub openrouter: ProviderConfig,
+    pub groq: ProviderConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderConfig {
+    /// The provider is disabled without one.
+    pub api_key: Option<Secret>,
+    /// File holding the API key, read at startup.
+    pub api_key_file: Option<PathBuf>,
+    /// `None` uses the provider's public API.
+    pub base_url: Option<String>,
+    /// `None` uses the provider's default model.
+    pub model: Option<String>,
+    /// Models requests may pick besides the default; `*` allows any.
+    pub allowed_models: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub timeout_secs: u64,
+    pub max_retries: u32,
+    pub retry_base_ms: u64,
+    pub retry_max_ms: u64,
+    /// `provider` or `provider:model`, tried in order when a request fails.
+    pub fallback_models: Vec<String>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 60,
+            max_retries: 2,
+            retry_base_ms: 500,
+            retry_max_ms: 10_000,
+            fallback_models: Vec::new(),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            base_delay: Duration::from_millis(self.retry_base_ms),
+            max_delay: Duration::from_millis(self.retry_max_ms),
+        }
+    }
+
+    pub fn fallback_targets(&self) -> Result<Vec<FallbackTarget>, String> {
+        self.fallback_models.iter().map(|m| m.parse()).collect()
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MockConfig {
+    /// Providers answered locally: `openrouter`, `groq` or `*`.
+    pub providers: Vec<String>,
+    pub script: Option<PathBuf>,
+}
+
+impl MockConfig {
+    pub fn is_mocked(&self, provider: &str) -> bool {
+        self.providers.iter().any(|m| m == provider || m == "*")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParamsConfig {
+    /// Upper bound for `max_tokens` in requests.
+    pub max_tokens: u32,
+}
+
+impl Default for ParamsConfig {
+    fn default() -> Self {
+        Self { max_tokens: 4096 }
+    }
+}
+
+/// An optional file, such as the prompt templates or the price table.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSetting {
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConversationsConfig {
+    /// Idle conversations are dropped from memory after this long.
+    pub ttl_secs: u64,
+}
+
+impl Default for ConversationsConfig {
+    fn default() -> Self {
+        Self { ttl_secs: 3600 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SummarizeConfig {
+    /// Longer text is split into chunks that fit.
+    pub prompt_tokens: usize,
+}
+
+impl Default for SummarizeConfig {
+    fn default() -> Self {
+        Self {
+            prompt_tokens: 3000,
+        }
+    }
+}
+
+/// Where the response cache keeps its entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    Memory,
+    Disk,
+    Off,
+}
+
+impl FromStr for CacheMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "memory" => Ok(CacheMode::Memory),
+            "disk" => Ok(CacheMode::Disk),
+            "off" => Ok(CacheMode::Off),
+            other => Err(format!("expected memory, disk or off, not '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub mode: CacheMode,
+    /// Entries kept in memory.
+    pub capacity: usize,
+    /// Directory of the disk cache.
+    pub dir: PathBuf,
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            mode: CacheMode::Memory,
+            capacity: 1000,
+            dir: PathBuf::from("cache"),
+            ttl_secs: 3600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// `label=sha256` pairs.
+    pub keys: Vec<String>,
+    /// TOML file of keys that can also be restricted to routes and models.
+    pub keys_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadinessConfig {
+    /// How long a provider probe is reused.
+    pub cache_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            cache_secs: 30,
+            timeout_secs: 5,
+        }
+    }
+}
+
+/// Looks up environment variables; tests pass a map instead.
+type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Parses `var` into `field` when it is set.
+fn set<T>(field: &mut T, env: Env, var: &str) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = env(var) {
+        *field = value
+            .trim()
+            .parse()
+            .map_err(|e| format!("{}='{}': {}", var, value, e))?;
+    }
+    Ok(())
+}
+
+/// Like `set`, for settings that are off when left out.
+fn set_some<T>(field: &mut Option<T>, env: Env, var: &str) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = env(var) {
+        *field = Some(
+            value
+                .trim()
+                .parse()
+                .map_err(|e| format!("{}='{}': {}", var, value, e))?,
+        );
+    }
+    Ok(())
+}
+
+/// Like `set`, for on/off settings written as `true`/`false` or `1`/`0`.
+fn set_flag(field: &mut bool, env: Env, var: &str) -> Result<(), String> {
+    if let Some(value) = env(var) {
+        *field = match value.trim() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => return Err(format!("{}='{}': expected true, false, 1 or 0", var, value)),
+        };
+    }
+    Ok(())
+}
+
+/// Replaces `field` with the comma-separated list in `var` when it is set.
+fn set_list(field: &mut Vec<String>, env: Env, var: &str) {
+    if let Some(value) = env(var) {
+        *field = cors::split_list(&value);
+    }
+}
+
+impl Config {
+    /// Reads the file, applies the environment and checks the result.
+    pub fn load() -> Result<Self, String> {
+        let path = match std::env::var("CONFIG_PATH") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from("rustybot.toml")).filter(|p| p.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env(&|var| std::env::var(var).ok())?;
+        config.resolve_files()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut config = Self::from_toml_str(&contents)
+            .map_err(|e| format!("Invalid configuration {}: {}", path.display(), e))?;
+        config.source = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    pub fn from_toml_str(contents: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for (name, provider) in config.provider_configs() {
+            if provider.api_key.is_some() && provider.api_key_file.is_some() {
+                return Err(format!(
+                    "providers.{} sets both api_key and api_key_file",
+                    name
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overrides settings with the environment variables read through `env`.
+    pub fn apply_env(&mut self, env: Env) -> Result<(), String> {
+        set(&mut self.server.host, env, "HOST")?;
+        set(&mut self.server.port, env, "PORT")?;
+        set(&mut self.server.static_dir, env, "STATIC_DIR")?;
+        set(&mut self.server.log_format, env, "LOG_FORMAT")?;
+        set_list(&mut self.server.trusted_proxies, env, "TRUSTED_PROXIES");
+        set(&mut self.database.path, env, "DATABASE_PATH")?;
+
+        for (provider, prefix) in [
+            (&mut self.providers.openrouter, "OPENROUTER"),
+            (&mut self.providers.groq, "GROQ"),
+        ] {
+            // A key in the environment replaces one from the file, however
+            // the file gave it; blank keys count as unset.
+            let file_var = format!("{}_API_KEY_FILE", prefix);
+            if let Some(path) = env(&file_var) {
+                provider.api_key = None;
+                provider.api_key_file = Some(PathBuf::from(path.trim()));
+            }
+            if let Some(key) = env(&format!("{}_API_KEY", prefix)) {
+                if !key.trim().is_empty() {
+                    provider.api_key = Some(Secret::new(key.trim()));
+                    provider.api_key_file = None;
+                }
+            }
+            set_some(&mut provider.base_url, env, &format!("{}_BASE_URL", prefix))?;
+            set_list(
+                &mut provider.allowed_models,
+                env,
+                &format!("{}_ALLOWED_MODELS", prefix),
+            );
+        }
+        // OpenRouter's model predates the other provider settings.
+        set_some(&mut self.providers.openrouter.model, env, "MODEL")?;
+        set_some(&mut self.providers.groq.model, env, "GROQ_MODEL")?;
+
+        set(
+            &mut self.upstream.timeout_secs,
+            env,
+            "UPSTREAM_TIMEOUT_SECS",
+        )?;
+        set(&mut self.upstream.max_retries, env, "UPSTREAM_MAX_RETRIES")?;
+        set(
+            &mut self.upstream.retry_base_ms,
+            env,
+            "UPSTREAM_RETRY_BASE_MS",
+        )?;
+        set(
+            &mut self.upstream.retry_max_ms,
+            env,
+            "UPSTREAM_RETRY_MAX_MS",
+        )?;
+        set_list(&mut self.upstream.fallback_models, env, "FALLBACK_MODELS");
+
+        set_list(&mut self.mock.providers, env, "MOCK_PROVIDERS");
+        set_some(&mut self.mock.script, env, "MOCK_SCRIPT")?;
+        set(&mut self.params.max_tokens, env, "MAX_TOKENS_LIMIT")?;
+        set_some(&mut self.templates.path, env, "PROMPTS_PATH")?;
+        set_some(&mut self.prices.path, env, "PRICES_PATH")?;
+        set(
+            &mut self.conversations.ttl_secs,
+            env,
+            "CONVERSATION_TTL_SECS",
+        )?;
+        set(
+            &mut self.summarize.prompt_tokens,
+            env,
+            "SUMMARY_PROMPT_TOKENS",
+        )?;
+
+        set(&mut self.cache.mode, env, "RESPONSE_CACHE")?;
+        set(&mut self.cache.capacity, env, "RESPONSE_CACHE_CAPACITY")?;
+        set(&mut self.cache.dir, env, "RESPONSE_CACHE_DIR")?;
+        set(&mut self.cache.ttl_secs, env, "RESPONSE_CACHE_TTL_SECS")?;
+
+        set_list(&mut self.auth.keys, env, "API_KEYS");
+        set_some(&mut self.auth.keys_path, env, "API_KEYS_PATH")?;
+
+        set_list(&mut self.cors.origins, env, "CORS_ALLOWED_ORIGINS");
+        set_list(&mut self.cors.methods, env, "CORS_ALLOWED_METHODS");
+        set_list(&mut self.cors.headers, env, "CORS_ALLOWED_HEADERS");
+        set_some(&mut self.cors.max_age_secs, env, "CORS_MAX_AGE_SECS")?;
+        set_flag(
+            &mut self.cors.allow_credentials,
+            env,
+            "CORS_ALLOW_CREDENTIALS",
+        )?;
+
+        // An empty list turns rate limiting off.
+        if let Some(list) = env("RATE_LIMITS") {
+            self.rate_limits = cors::split_list(&list)
+                .into_iter()
+                .map(|entry| match entry.split_once('=') {
+                    Some((route, quota)) => {
+                        Ok((route.trim().to_string(), quota.trim().to_string()))
+                    }
+                    None => Err(format!(
+                        "RATE_LIMITS: invalid entry '{}', expected route=requests/seconds",
+                        entry
+                    )),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        set(&mut self.readiness.cache_secs, env, "READINESS_CACHE_SECS")?;
+        set(
+            &mut self.readiness.timeout_secs,
+            env,
+            "READINESS_TIMEOUT_SECS",
+        )?;
+        Ok(())
+    }
+
+    /// Reads API keys from their files, and picks up `prompts.toml` and
+    /// `prices.toml` in the working directory when no path is set.
+    pub fn resolve_files(&mut self) -> Result<(), String> {
+        for (name, provider) in [
+            ("openrouter", &mut self.providers.openrouter),
+            ("groq", &mut self.providers.groq),
+        ] {
+            let Some(path) = &provider.api_key_file else {
+                continue;
+            };
+            let key = std::fs::read_to_string(path).map_err(|e| {
+                format!(
+                    "providers.{}.api_key_file: Failed to read {}: {}",
+                    name,
+                    path.display(),
+                    e
+                )
+            })?;
+            if key.trim().is_empty() {
+                return Err(format!(
+                    "providers.{}.api_key_file: {} is empty",
+                    name,
+                    path.display()
+                ));
+            }
+            provider.api_key = Some(Secret::new(key.trim()));
+        }
+        if self.templates.path.is_none() {
+            self.templates.path = Some(PathBuf::from("prompts.toml")).filter(|p| p.exists());
+        }
+        if self.prices.path.is_none() {
+            self.prices.path = Some(PathBuf::from("prices.toml")).filter(|p| p.exists());
+        }
+        Ok(())
+    }
+
+    /// Checks the settings that can be checked without files or the network,
+    /// reporting every problem at once.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        for (name, provider) in self.provider_configs() {
+            if let Some(url) = &provider.base_url {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    errors.push(format!(
+                        "providers.{}.base_url must start with http:// or https://, not '{}'",
+                        name, url
+                    ));
+                }
+            }
+            if provider.model.as_deref() == Some("") {
+                errors.push(format!("providers.{}.model must not be empty", name));
+            }
+        }
+        if self.upstream.timeout_secs == 0 {
+            errors.push("upstream.timeout_secs must be at least 1".to_string());
+        }
+        if self.upstream.retry_base_ms > self.upstream.retry_max_ms {
+            errors.push("upstream.retry_base_ms must not exceed upstream.retry_max_ms".to_string());
+        }
+        match self.upstream.fallback_targets() {
+            Ok(targets) => {
+                for target in targets {
+                    if !PROVIDERS.contains(&target.provider.as_str()) {
+                        errors.push(format!(
+                            "upstream.fallback_models: unknown provider '{}'",
+                            target.provider
+                        ));
+                    }
+                }
+            }
+            Err(e) => errors.push(format!("upstream.fallback_models: {}", e)),
+        }
+        for name in &self.mock.providers {
+            if name != "*" && !PROVIDERS.contains(&name.as_str()) {
+                errors.push(format!("mock.providers: unknown provider '{}'", name));
+            }
+        }
+        if self.params.max_tokens == 0 {
+            errors.push("params.max_tokens must be at least 1".to_string());
+        }
+        if self.summarize.prompt_tokens < MIN_PROMPT_TOKENS {
+            errors.push(format!(
+                "summarize.prompt_tokens must be at least {}",
+                MIN_PROMPT_TOKENS
+            ));
+        }
+        if self.cache.mode == CacheMode::Memory && self.cache.capacity == 0 {
+            errors.push("cache.capacity must be at least 1 for the memory cache".to_string());
+        }
+        if self.readiness.timeout_secs == 0 {
+            errors.push("readiness.timeout_secs must be at least 1".to_string());
+        }
+        if let Err(e) = RateLimits::new(self.rate_limit_entries()) {
+            errors.push(format!("rate_limits: {}", e));
+        }
+        if let Err(e) = TrustedProxies::parse(&self.server.trusted_proxies) {
+            errors.push(format!("server.trusted_proxies: {}", e));
+        }
+        if let Err(e) = self.cors.clone().build() {
+            errors.push(format!("cors: {}", e));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+
+    pub fn rate_limits(&self) -> Result<RateLimits, String> {
+        let proxies = TrustedProxies::parse(&self.server.trusted_proxies)?;
+        Ok(RateLimits::new(self.rate_limit_entries())?.with_trusted_proxies(proxies))
+    }
+
+    fn rate_limit_entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rate_limits
+            .iter()
+            .map(|(route, quota)| (route.as_str(), quota.as_str()))
+    }
+
+    fn provider_configs(&self) -> [(&'static str, &ProviderConfig); 2] {
+        [
+            ("openrouter", &self.providers.openrouter),
+            ("groq", &self.providers.groq),
+        ]
+    }
+}
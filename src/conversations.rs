@@ -0,0 +1,127 @@
+//! In-memory conversation sessions so users can ask follow-up questions.
+//!
+//! Each conversation remembers its owner (the API key label that started it),
+//! its provider and every question/answer pair.
+//! Conversations that have been idle for longer than the TTL are evicted,
+//! both lazily on access and by a periodic sweep started in `main`. Evicted
+//! conversations are not lost: `storage` keeps them on disk and handlers
+//! `restore` them on the next message.
+
+use crate::providers::{ChatMessage, Role};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Conversation {
+    pub id: String,
+    pub owner: String,
+    pub provider: String,
+    pub turns: Vec<Turn>,
+    last_active: Instant,
+}
+
+impl Conversation {
+    pub fn new(id: String, owner: String, provider: String, turns: Vec<Turn>) -> Self {
+        Self {
+            id,
+            owner,
+            provider,
+            turns,
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Prior turns replayed as alternating user/assistant chat messages.
+    pub fn history(&self) -> Vec<ChatMessage> {
+        self.turns
+            .iter()
+            .flat_map(|turn| {
+                [
+                    ChatMessage::new(Role::User, turn.question.clone()),
+                    ChatMessage::new(Role::Assistant, turn.answer.clone()),
+                ]
+            })
+            .collect()
+    }
+}
+
+pub struct ConversationStore {
+    ttl: Duration,
+    conversations: Mutex<HashMap<String, Conversation>>,
+}
+
+impl ConversationStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            conversations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a new, empty conversation for `owner` and returns its id.
+    pub fn create(&self, owner: &str, provider: &str) -> String {
+        let id = Uuid::new_v4().to_string();
+        self.restore(Conversation::new(
+            id.clone(),
+            owner.to_string(),
+            provider.to_string(),
+            Vec::new(),
+        ));
+        id
+    }
+
+    /// Puts a conversation (e.g. one loaded from storage) back into memory.
+    pub fn restore(&self, conversation: Conversation) {
+        self.conversations
+            .lock()
+            .unwrap()
+            .insert(conversation.id.clone(), conversation);
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.conversations.lock().unwrap().remove(id);
+    }
+
+    /// Returns a snapshot of the conversation, or `None` if it is unknown or expired.
+    pub fn get(&self, id: &str) -> Option<Conversation> {
+        let mut conversations = self.conversations.lock().unwrap();
+        match conversations.get(id) {
+            Some(c) if c.last_active.elapsed() > self.ttl => {
+                conversations.remove(id);
+                None
+            }
+            Some(c) => Some(c.clone()),
+            None => None,
+        }
+    }
+
+    /// Records a finished turn. Returns `false` if the conversation expired meanwhile.
+    pub fn append(&self, id: &str, turn: Turn) -> bool {
+        let mut conversations = self.conversations.lock().unwrap();
+        match conversations.get_mut(id) {
+            Some(c) => {
+                c.turns.push(turn);
+                c.last_active = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops every conversation idle for longer than the TTL; returns how many.
+    pub fn evict_expired(&self) -> usize {
+        let mut conversations = self.conversations.lock().unwrap();
+        let before = conversations.len();
+        conversations.retain(|_, c| c.last_active.elapsed() <= self.ttl);
+        before - conversations.len()
+    }
+}
@@ -0,0 +1,321 @@
+//! Cross-origin policy for browsers calling the API from other sites.
+//!
+//! `CorsConfig` holds the raw settings; `build` checks them once at startup
+//! and turns them into a `CorsPolicy`, which creates the `actix_cors::Cors`
+//! middleware for every worker. Origins are exact (`https://example.com`),
+//! wildcard subdomains (`https://*.example.com`, which does not match
+//! `https://example.com` itself) or `*` for any origin. Settings a browser
+//! would refuse, such as credentials together with any origin, or an origin
+//! with a path that could never match, stop the server with a clear error.
+
+use actix_cors::Cors;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Response headers scripts on other origins may read.
+const EXPOSED_HEADERS: [&str; 6] = [
+    "x-request-id",
+    "retry-after",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+    "ratelimit-policy",
+];
+
+/// An allowed value of the `Origin` request header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OriginPattern {
+    Any,
+    Exact(String),
+    /// `scheme://*.suffix`: any subdomain of `suffix`, at any depth.
+    Subdomains {
+        scheme: String,
+        suffix: String,
+    },
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let origin = s.trim();
+        if origin == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        let invalid = |reason: &str| format!("invalid CORS origin '{}': {}", origin, reason);
+        let (scheme, host) = origin
+            .split_once("://")
+            .ok_or_else(|| invalid("expected scheme://host[:port]"))?;
+        if scheme != "http" && scheme != "https" {
+            return Err(invalid("scheme must be http or https"));
+        }
+        // Browsers send the bare origin, so a path or trailing slash never matches.
+        if host.is_empty() || host.contains(['/', '?', '#']) {
+            return Err(invalid("use scheme://host[:port] without a path"));
+        }
+        if origin.chars().any(|c| c.is_ascii_uppercase()) {
+            return Err(invalid("browsers send origins in lowercase"));
+        }
+        match host.strip_prefix("*.") {
+            Some(suffix) if !suffix.is_empty() && !suffix.contains('*') => {
+                Ok(OriginPattern::Subdomains {
+                    scheme: scheme.to_string(),
+                    suffix: suffix.to_string(),
+                })
+            }
+            Some(_) => Err(invalid("a wildcard needs a domain after '*.'")),
+            None if host.contains('*') => Err(invalid(
+                "'*' is only allowed as the first label, as in https://*.example.com",
+            )),
+            None => Ok(OriginPattern::Exact(origin.to_string())),
+        }
+    }
+}
+
+impl OriginPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(allowed) => allowed == origin,
+            OriginPattern::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        }
+    }
+}
+
+/// CORS settings as configured, before validation.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origin patterns; empty allows no cross-origin requests.
+    pub origins: Vec<String>,
+    /// Allowed methods; `*` allows any.
+    pub methods: Vec<String>,
+    /// Allowed request headers; `*` allows any.
+    pub headers: Vec<String>,
+    /// How long browsers may cache a preflight response.
+    pub max_age_secs: Option<usize>,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: vec!["*".to_string()],
+            methods: ["GET", "POST", "DELETE"].map(String::from).to_vec(),
+            headers: ["authorization", "content-type"].map(String::from).to_vec(),
+            max_age_secs: Some(3600),
+            allow_credentials: false,
+        }
+    }
+}
+
+/// Splits a comma-separated setting, dropping empty entries.
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl CorsConfig {
+    pub fn build(self) -> Result<CorsPolicy, String> {
+        let origins = self
+            .origins
+            .iter()
+            .map(|origin| origin.parse())
+            .collect::<Result<Vec<OriginPattern>, _>>()?;
+        let any_origin = origins.contains(&OriginPattern::Any);
+        if any_origin && origins.len() > 1 {
+            return Err("CORS origin '*' cannot be combined with other origins".to_string());
+        }
+        if any_origin && self.allow_credentials {
+            return Err(
+                "CORS credentials cannot be allowed for any origin ('*'); list the origins instead"
+                    .to_string(),
+            );
+        }
+
+        let methods = if self.methods.iter().any(|m| m == "*") {
+            None
+        } else {
+            let methods = self
+                .methods
+                .iter()
+                .map(|m| {
+                    Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+                        .map_err(|_| format!("invalid CORS method '{}'", m))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(methods)
+        };
+
+        let headers = if self.headers.iter().any(|h| h == "*") {
+            None
+        } else {
+            let headers = self
+                .headers
+                .iter()
+                .map(|h| {
+                    HeaderName::from_str(h).map_err(|_| format!("invalid CORS header '{}'", h))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(headers)
+        };
+
+        Ok(CorsPolicy {
+            origins,
+            methods,
+            headers,
+            max_age_secs: self.max_age_secs,
+            allow_credentials: self.allow_credentials,
+        })
+    }
+}
+
+/// A validated `CorsConfig`.
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    origins: Vec<OriginPattern>,
+    /// `None` allows any method.
+    methods: Option<Vec<Method>>,
+    /// `None` allows any header.
+    headers: Option<Vec<HeaderName>>,
+    max_age_secs: Option<usize>,
+    allow_credentials: bool,
+}
+
+impl CorsPolicy {
+    /// The middleware for one worker.
+    pub fn cors(&self) -> Cors {
+        // Requests from other origins still reach the handlers, they just get
+        // no CORS headers. Blocking them would also block the landing page,
+        // since browsers send `Origin` on same-origin POSTs too.
+        let mut cors = Cors::default()
+            .block_on_origin_mismatch(false)
+            .expose_headers(EXPOSED_HEADERS)
+            .max_age(self.max_age_secs);
+
+        if self.origins.contains(&OriginPattern::Any) {
+            cors = cors.allow_any_origin().send_wildcard();
+        } else if !self.origins.is_empty() {
+            let origins = self.origins.clone();
+            cors = cors.allowed_origin_fn(move |origin: &HeaderValue, _| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| origins.iter().any(|pattern| pattern.matches(origin)))
+            });
+        }
+
+        // For "any", actix-cors lists every method and echoes the requested
+        // headers rather than sending `*`, which browsers ignore on
+        // credentialed requests.
+        cors = match &self.methods {
+            None => cors.allow_any_method(),
+            Some(methods) => cors.allowed_methods(methods.clone()),
+        };
+        cors = match &self.headers {
+            None => cors.allow_any_header(),
+            Some(headers) => cors.allowed_headers(headers.clone()),
+        };
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+        cors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    fn policy(origins: &[&str], allow_credentials: bool) -> Result<CorsPolicy, String> {
+        CorsConfig {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        }
+        .build()
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let pattern: OriginPattern = "https://*.example.com".parse().unwrap();
+        assert!(pattern.matches("https://app.example.com"));
+        assert!(pattern.matches("https://a.b.example.com"));
+        assert!(!pattern.matches("https://example.com"));
+        assert!(!pattern.matches("https://badexample.com"));
+        assert!(!pattern.matches("http://app.example.com"));
+
+        let pattern: OriginPattern = "http://localhost:3000".parse().unwrap();
+        assert!(pattern.matches("http://localhost:3000"));
+        assert!(!pattern.matches("http://localhost:3001"));
+    }
+
+    #[test]
+    fn origins_a_browser_never_sends_are_rejected() {
+        for (origin, reason) in [
+            ("example.com", "expected scheme://host[:port]"),
+            ("ftp://example.com", "scheme must be http or https"),
+            ("https://example.com/", "without a path"),
+            ("https://Example.com", "lowercase"),
+            ("https://*.", "a wildcard needs a domain"),
+            (
+                "https://app.*.example.com",
+                "only allowed as the first label",
+            ),
+        ] {
+            let error = origin.parse::<OriginPattern>().unwrap_err();
+            assert!(error.contains(reason), "{}: {}", origin, error);
+        }
+    }
+
+    #[test]
+    fn build_rejects_unsafe_combinations() {
+        let error = policy(&["*", "https://example.com"], false).unwrap_err();
+        assert!(error.contains("cannot be combined"), "{}", error);
+        let error = policy(&["*"], true).unwrap_err();
+        assert!(error.contains("credentials"), "{}", error);
+        assert!(policy(&["https://example.com"], true).is_ok());
+
+        let error = CorsConfig {
+            headers: vec!["bad header".to_string()],
+            ..CorsConfig::default()
+        }
+        .build()
+        .unwrap_err();
+        assert_eq!(error, "invalid CORS header 'bad header'");
+    }
+
+    #[actix_web::test]
+    async fn only_allowed_origins_get_cors_headers() {
+        let policy = policy(&["https://*.example.com"], true).unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(policy.cors())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for (origin, allowed) in [
+            ("https://app.example.com", Some("https://app.example.com")),
+            ("https://evil.com", None),
+        ] {
+            let request = TestRequest::get()
+                .uri("/")
+                .insert_header(("origin", origin))
+                .to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(response.status(), 200, "{}", origin);
+            let header = response.headers().get("access-control-allow-origin");
+            assert_eq!(header.map(|v| v.to_str().unwrap()), allowed, "{}", origin);
+        }
+    }
+}
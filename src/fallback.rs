@@ -0,0 +1,214 @@
+//! Ordered fallback across (provider, model) pairs when an upstream fails.
+//!
+//! A request is first sent to the provider the endpoint belongs to. If that
+//! times out, cannot be reached, is rate limited (429) or fails with a 5xx,
+//! the same request is retried on each configured fallback in order until
+//! one answers. Client errors such as a 400 are returned right away, since
+//! another model would reject the request just the same. Fallbacks whose
+//! model the parameter policy or the caller's API key does not allow are
+//! skipped, just as a request for that model would be rejected.
+
+use crate::auth::Principal;
+use crate::params::ParamPolicy;
+use crate::providers::{ChatRequest, LlmProvider, ProviderError, ProviderRegistry};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// One entry of the fallback list, written as `provider` or `provider:model`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FallbackTarget {
+    pub provider: String,
+    /// `None` uses the provider's default model.
+    pub model: Option<String>,
+}
+
+impl FromStr for FallbackTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split on the first colon only: OpenRouter ids like `x/y:free` contain one.
+        let (provider, model) = match s.trim().split_once(':') {
+            Some((provider, model)) => (provider.trim(), Some(model.trim().to_string())),
+            None => (s.trim(), None),
+        };
+        if provider.is_empty() || model.as_deref() == Some("") {
+            return Err(format!(
+                "invalid fallback '{}', expected provider[:model]",
+                s
+            ));
+        }
+        Ok(Self {
+            provider: provider.to_string(),
+            model,
+        })
+    }
+}
+
+/// An attempt that failed before another provider answered.
+#[derive(Clone, Debug, Serialize)]
+pub struct FailedAttempt {
+    pub provider: String,
+    pub model: String,
+    pub error: String,
+}
+
+/// A successful result and how it was reached.
+pub struct Answered<T> {
+    pub value: T,
+    pub provider: &'static str,
+    pub model: String,
+    pub failed_attempts: Vec<FailedAttempt>,
+}
+
+/// Every attempt failed, or the first failure was not worth retrying.
+#[derive(Debug)]
+pub struct FallbackError {
+    pub error: ProviderError,
+    /// All failed attempts, the one in `error` included.
+    pub attempts: Vec<FailedAttempt>,
+}
+
+impl fmt::Display for FallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for FallbackError {}
+
+impl ResponseError for FallbackError {
+    fn status_code(&self) -> StatusCode {
+        self.error.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Without fallbacks the body is exactly what the provider error gives.
+        if self.attempts.len() < 2 {
+            return self.error.error_response();
+        }
+        let message = match &self.error {
+            ProviderError::Upstream { body, .. } => body.clone(),
+            other => other.to_string(),
+        };
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": message,
+            "failed_attempts": self.attempts,
+        }))
+    }
+}
+
+/// Whether `error` may go away on a different provider or model.
+pub fn is_retryable(error: &ProviderError) -> bool {
+    match error {
+        ProviderError::Timeout | ProviderError::Request(_) => true,
+        ProviderError::Upstream { status, .. } => {
+            *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        }
+        ProviderError::InvalidResponse(_) => false,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FallbackChain {
+    targets: Vec<FallbackTarget>,
+}
+
+impl FallbackChain {
+    /// Checks that every target names a registered provider.
+    pub fn new(targets: Vec<FallbackTarget>, registry: &ProviderRegistry) -> Result<Self, String> {
+        // Disabled providers are accepted and skipped when the chain runs.
+        if let Some(unknown) = targets.iter().find(|t| !registry.is_known(&t.provider)) {
+            return Err(format!("unknown fallback provider '{}'", unknown.provider));
+        }
+        Ok(Self { targets })
+    }
+
+    pub fn targets(&self) -> &[FallbackTarget] {
+        &self.targets
+    }
+
+    /// Runs `call` on `primary`, then on each fallback while the failures are retryable.
+    ///
+    /// Fallbacks get the same request with the model replaced by their own;
+    /// sampling options carry over unchanged. Only fallbacks that both
+    /// `policy` and `principal` allow are tried.
+    pub async fn run<T, F, Fut>(
+        &self,
+        registry: &ProviderRegistry,
+        policy: &ParamPolicy,
+        principal: &Principal,
+        primary: Arc<dyn LlmProvider>,
+        request: ChatRequest,
+        mut call: F,
+    ) -> Result<Answered<T>, FallbackError>
+    where
+        F: FnMut(Arc<dyn LlmProvider>, ChatRequest) -> Fut,
+        Fut: Future<Output = Result<T, ProviderError>>,
+    {
+        let mut candidates = vec![(primary, request.clone())];
+        for target in &self.targets {
+            if let Some(provider) = registry.get(&target.provider) {
+                let model = target.model.as_deref().unwrap_or(provider.default_model());
+                if policy.check_model(provider.as_ref(), model).is_err()
+                    || principal.check_model(model).is_err()
+                {
+                    continue;
+                }
+                let mut request = request.clone();
+                request.options.model = target.model.clone();
+                candidates.push((provider, request));
+            }
+        }
+
+        let mut attempts: Vec<FailedAttempt> = Vec::new();
+        let mut last_error = None;
+        for (provider, request) in candidates {
+            let model = request
+                .options
+                .model
+                .clone()
+                .unwrap_or_else(|| provider.default_model().to_string());
+            // A pair that already failed is not asked again.
+            if attempts
+                .iter()
+                .any(|a| a.provider == provider.name() && a.model == model)
+            {
+                continue;
+            }
+
+            match call(provider.clone(), request).await {
+                Ok(value) => {
+                    return Ok(Answered {
+                        value,
+                        provider: provider.name(),
+                        model,
+                        failed_attempts: attempts,
+                    })
+                }
+                Err(error) => {
+                    tracing::warn!(provider = provider.name(), model = %model, error = %error, "provider failed");
+                    attempts.push(FailedAttempt {
+                        provider: provider.name().to_string(),
+                        model,
+                        error: error.to_string(),
+                    });
+                    let retryable = is_retryable(&error);
+                    last_error = Some(error);
+                    if !retryable {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Err(FallbackError {
+            error: last_error.expect("the primary is always attempted"),
+            attempts,
+        })
+    }
+}
@@ -0,0 +1,148 @@
+//! Liveness and readiness reports.
+//!
+//! `/healthz` only says the process answers. `/readyz` checks what requests
+//! depend on: the static site, the database and every provider, which is
+//! asked for its model list. The server stays ready while at least one
+//! enabled provider answers. Provider probes are cut off after a timeout and
+//! their results reused for a while, so frequent health checks do not turn
+//! into a stream of upstream calls.
+
+use crate::providers::LlmProvider;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    /// Not configured, e.g. a provider without an API key. Does not make the
+    /// server unready.
+    Disabled,
+    Failed,
+}
+
+/// The result of checking one dependency.
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// How old a cached probe result is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_secs: Option<u64>,
+}
+
+impl Check {
+    fn new(status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            detail: Some(detail.into()),
+            latency_ms: None,
+            age_secs: None,
+        }
+    }
+
+    pub fn ok(detail: impl Into<String>) -> Self {
+        Self::new(CheckStatus::Ok, detail)
+    }
+
+    pub fn disabled(detail: impl Into<String>) -> Self {
+        Self::new(CheckStatus::Disabled, detail)
+    }
+
+    pub fn failed(detail: impl Into<String>) -> Self {
+        Self::new(CheckStatus::Failed, detail)
+    }
+}
+
+/// What `/readyz` returns.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Some enabled providers failed, but at least one still answers.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub degraded: bool,
+    pub checks: BTreeMap<String, Check>,
+}
+
+impl Readiness {
+    /// Ready unless one of `checks` failed or every enabled provider did.
+    /// Providers are listed as `provider:<name>`.
+    pub fn new(mut checks: BTreeMap<String, Check>, providers: Vec<(&str, Check)>) -> Self {
+        let enabled = providers
+            .iter()
+            .filter(|(_, c)| c.status != CheckStatus::Disabled)
+            .count();
+        let failed = providers
+            .iter()
+            .filter(|(_, c)| c.status == CheckStatus::Failed)
+            .count();
+        let ready = checks.values().all(|c| c.status != CheckStatus::Failed) && failed < enabled;
+        for (name, check) in providers {
+            checks.insert(format!("provider:{}", name), check);
+        }
+        Self {
+            ready,
+            degraded: ready && failed > 0,
+            checks,
+        }
+    }
+}
+
+/// Checks that `dir` holds the landing page.
+pub fn check_static_dir(dir: &Path) -> Check {
+    if dir.join("index.html").is_file() {
+        Check::ok(dir.display().to_string())
+    } else {
+        Check::failed(format!("{} not found", dir.join("index.html").display()))
+    }
+}
+
+/// Asks providers for their model list, remembering each answer for `ttl`.
+pub struct ProviderProbes {
+    ttl: Duration,
+    timeout: Duration,
+    results: Mutex<HashMap<&'static str, (Instant, Check)>>,
+}
+
+impl ProviderProbes {
+    pub fn new(ttl: Duration, timeout: Duration) -> Self {
+        Self {
+            ttl,
+            timeout,
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn check(&self, provider: &dyn LlmProvider) -> Check {
+        if let Some((taken, check)) = self.results.lock().unwrap().get(provider.name()) {
+            if taken.elapsed() < self.ttl {
+                return Check {
+                    age_secs: Some(taken.elapsed().as_secs()),
+                    ..check.clone()
+                };
+            }
+        }
+
+        let started = Instant::now();
+        let mut check = match tokio::time::timeout(self.timeout, provider.list_models()).await {
+            Ok(Ok(models)) => Check::ok(format!("{} models available", models.len())),
+            Ok(Err(e)) => Check::failed(e.to_string()),
+            Err(_) => Check::failed(format!("no answer within {}s", self.timeout.as_secs_f64())),
+        };
+        check.latency_ms = Some(started.elapsed().as_millis() as u64);
+        self.results
+            .lock()
+            .unwrap()
+            .insert(provider.name(), (Instant::now(), check.clone()));
+        Check {
+            age_secs: Some(0),
+            ..check
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Shared building blocks for the Rusty Bot server in `main.rs`.
+
+pub mod app;
+pub mod auth;
+pub mod cache;
+pub mod cli;
+pub mod config;
+pub mod conversations;
+pub mod cors;
+pub mod fallback;
+pub mod health;
+pub mod metrics;
+pub mod openai;
+pub mod params;
+pub mod providers;
+pub mod rate_limit;
+pub mod sse;
+pub mod storage;
+pub mod summarize;
+pub mod telemetry;
+pub mod templates;
+pub mod usage;
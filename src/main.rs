@@ -1,209 +1,295 @@
-use llm_chain::{
-    chains::sequential::Chain,
-    options::{Opt, Options},
-    prompt,
-    step::Step,
-    Parameters,
+use actix_web::{web, App, HttpServer};
+use openrouter_rust_demo::app::{self, AppState};
+use openrouter_rust_demo::providers::{
+    GroqProvider, MockProvider, MockScript, OpenRouterProvider, ProviderRegistry,
 };
-use llm_chain_openai::chatgpt::{Executor, Model};
-use async_openai::config::OpenAIConfig;
-use async_openai::Client;
-use actix_web::{web, App, HttpServer, HttpResponse, Result as ActixResult, middleware::Logger};
-use actix_files::Files;
-use actix_cors::Cors;
-use serde::{Deserialize, Serialize};
-use std::env;
+use openrouter_rust_demo::auth::ApiKeys;
+use openrouter_rust_demo::cli::{self, Command};
+use openrouter_rust_demo::cache::{DiskBackend, MemoryBackend, ResponseCache};
+use openrouter_rust_demo::config::{CacheMode, Config};
+use openrouter_rust_demo::conversations::ConversationStore;
+use openrouter_rust_demo::fallback::FallbackChain;
+use openrouter_rust_demo::health::ProviderProbes;
+use openrouter_rust_demo::metrics::{metrics, RequestMetrics};
+use openrouter_rust_demo::params::ParamPolicy;
+use openrouter_rust_demo::storage::Storage;
+use openrouter_rust_demo::summarize::{ProviderExecutor, Summarizer};
+use openrouter_rust_demo::telemetry::{self, LogTarget, RequestTracing};
+use openrouter_rust_demo::templates::PromptLibrary;
+use openrouter_rust_demo::usage::PriceTable;
 use std::sync::Arc;
-use serde_json::json;
+use std::time::Duration;
+use tracing::{info, warn};
 
-#[derive(Deserialize)]
-struct CompletionRequest {
-    question: String,
-}
+#[actix_web::main]
+async fn main() -> anyhow::Result<()> {
+    // `serve` (the default), `ask` or `chat`; see cli.rs.
+    let command = Command::parse(std::env::args().skip(1)).map_err(anyhow::Error::msg)?;
+    if let Command::Help = command {
+        print!("{}", cli::USAGE);
+        return Ok(());
+    }
 
-#[derive(Serialize)]
-struct CompletionResponse {
-    answer: String,
-}
+    // Load .env if present
+    dotenv::dotenv().ok();
 
-#[derive(Clone)]
-struct AppState {
-    executor: Arc<Executor>,
-    chain: Arc<Chain>,
-}
+    // Settings come from rustybot.toml (or CONFIG_PATH) and the environment,
+    // which wins; see config.rs and the README for every setting.
+    let config = Config::load().map_err(anyhow::Error::msg)?;
 
-async fn completion(
-    req: web::Json<CompletionRequest>,
-    state: web::Data<AppState>,
-) -> ActixResult<HttpResponse> {
-    let parameters = Parameters::new()
-        .with("question", &req.question);
-
-    let output = state
-        .chain
-        .as_ref()
-        .run(parameters, state.executor.as_ref())
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
-
-    let answer = output
-        .to_immediate()
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?
-        .primary_textual_output()
-        .unwrap_or_else(|| "No answer received".to_string());
-
-    Ok(HttpResponse::Ok().json(CompletionResponse { answer }))
-}
+    // Logs go to stdout as text, or as JSON lines with LOG_FORMAT=json, and
+    // RUST_LOG filters them (default "info"). The CLI logs warnings to stderr
+    // instead, keeping stdout for answers. Setting OTEL_EXPORTER_OTLP_ENDPOINT
+    // (e.g. http://localhost:4318) also exports spans to that collector.
+    let target = match command {
+        Command::Serve => LogTarget::Server,
+        _ => LogTarget::Terminal,
+    };
+    let telemetry = telemetry::init(config.server.log_format, target).map_err(anyhow::Error::msg)?;
+    if telemetry.exporting() {
+        info!("exporting traces over OTLP");
+    }
+    match &config.source {
+        Some(path) => info!(path = %path.display(), "configuration loaded"),
+        None => info!("no rustybot.toml found, configured from the environment"),
+    }
 
-async fn greeter_with_name(nameparam: web::Path<String>) -> HttpResponse {
-    let name = nameparam.as_str();
-    HttpResponse::Ok().body(format!("Hello, {}!", name))
+    let app_state = build_state(config)?;
+    match command {
+        Command::Ask(args) => cli::run_ask(&app_state, args).await.map_err(anyhow::Error::msg),
+        Command::Chat(args) => cli::run_chat(&app_state, args).await.map_err(anyhow::Error::msg),
+        _ => serve(app_state).await,
+    }
 }
 
-async fn greeter_default() -> HttpResponse {
-    HttpResponse::Ok().body("Hello, world!")
-}
+/// Everything the HTTP handlers and the CLI share.
+fn build_state(config: Config) -> anyhow::Result<AppState> {
+    // -------------------------------------------------
+    // 1️⃣  Register the chat providers
+    // -------------------------------------------------
+    // Upstreams that take longer than the timeout to answer, or to start
+    // streaming, count as failed. Connect errors, 429s and 5xx are retried
+    // with backoff unless the upstream says how long to wait.
+    let upstream = &config.upstream;
 
-async fn groqlive(req: web::Json<CompletionRequest>) -> ActixResult<HttpResponse> {
-    let api_key = env::var("GROQ_API_KEY")
-        .map_err(|_| actix_web::error::ErrorInternalServerError("GROQ_API_KEY not set"))?;
-
-    let client = reqwest::Client::new();
-    
-    let request_body = json!({
-        "model": "groq/compound-mini",
-        "messages": [{
-            "role": "user",
-            "content": req.question
-        }]
+    // Each provider is enabled by its API key. Without one the server still
+    // starts: the provider's routes answer 503 and /providers says why.
+    // Mocked providers answer locally instead, following the mock script if
+    // set, so the server runs without keys or network.
+    let mock = &config.mock;
+    let mock_script = Arc::new(match &mock.script {
+        Some(path) => MockScript::load(path).map_err(anyhow::Error::msg)?,
+        None => MockScript::default(),
     });
 
-    let response = client
-        .post("https://api.groq.com/openai/v1/chat/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Request failed: {}", e)))?;
-
-    let status = response.status();
-    
-    if !status.is_success() {
-        let error_body = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        return Ok(HttpResponse::build(status)
-            .content_type("application/json")
-            .json(json!({ "error": error_body })));
+    let mut providers = ProviderRegistry::new();
+    let openrouter = &config.providers.openrouter;
+    let model = openrouter.model.clone().unwrap_or_else(|| OpenRouterProvider::DEFAULT_MODEL.to_string());
+    match &openrouter.api_key {
+        _ if mock.is_mocked("openrouter") => {
+            providers.register(Arc::new(MockProvider::new("openrouter", model, mock_script.clone())))
+        }
+        Some(key) => providers.register(Arc::new(
+            OpenRouterProvider::new(key.expose().to_string(), model)
+                .with_base_url(openrouter.base_url.as_deref().unwrap_or(OpenRouterProvider::DEFAULT_BASE_URL))
+                .with_timeout(upstream.timeout())
+                .with_retry(upstream.retry()),
+        )),
+        None => providers.disable("openrouter", "OPENROUTER_API_KEY not set"),
+    }
+    let groq = &config.providers.groq;
+    let groq_model = groq.model.clone().unwrap_or_else(|| GroqProvider::DEFAULT_MODEL.to_string());
+    match &groq.api_key {
+        _ if mock.is_mocked("groq") => providers.register(Arc::new(MockProvider::new("groq", groq_model, mock_script))),
+        Some(key) => providers.register(Arc::new(
+            GroqProvider::new(key.expose().to_string(), groq_model)
+                .with_base_url(groq.base_url.as_deref().unwrap_or(GroqProvider::DEFAULT_BASE_URL))
+                .with_timeout(upstream.timeout())
+                .with_retry(upstream.retry()),
+        )),
+        None => providers.disable("groq", "GROQ_API_KEY not set"),
+    }
+    for name in ["openrouter", "groq"].into_iter().filter(|name| mock.is_mocked(name)) {
+        info!(provider = name, "answering with the mock provider");
     }
 
-    let groq_response: serde_json::Value = response
-        .json()
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Failed to parse response: {}", e)))?;
-
-    // Extract the answer from Groq's response format
-    let answer = groq_response
-        .get("choices")
-        .and_then(|choices| choices.as_array())
-        .and_then(|arr| arr.get(0))
-        .and_then(|choice| choice.get("message"))
-        .and_then(|msg| msg.get("content"))
-        .and_then(|content| content.as_str())
-        .unwrap_or("No answer received")
-        .to_string();
-
-    Ok(HttpResponse::Ok().json(CompletionResponse { answer }))
-}
-
-#[actix_web::main]
-async fn main() -> anyhow::Result<()> {
-    // Load .env if present
-    dotenv::dotenv().ok();
+    // Requests may only pick the default model or an allowed one (`*` allows
+    // any), and ask for at most `params.max_tokens` tokens.
+    let params = ParamPolicy::new(config.params.max_tokens)
+        .allow_models("openrouter", openrouter.allowed_models.clone())
+        .allow_models("groq", groq.allowed_models.clone());
+    // Metrics name only these models, so clients picking arbitrary ones
+    // through a `*` allowlist cannot create unbounded series.
+    for provider in providers.iter() {
+        metrics().label_models(provider.name(), params.models(provider.as_ref()));
+    }
 
     // -------------------------------------------------
-    // 1️⃣  Build config pointing at OpenRouter
+    // 2️⃣  Load prompt templates - System prompts
     // -------------------------------------------------
-    let config = OpenAIConfig::new()
-        .with_api_key(env::var("OPENROUTER_API_KEY")?)
-        .with_api_base("https://openrouter.ai/api/v1");
+    let templates = match &config.templates.path {
+        Some(path) => PromptLibrary::load(path)?,
+        None => {
+            info!("no prompts.toml found, using the built-in prompt");
+            PromptLibrary::builtin()
+        }
+    };
+    info!(
+        templates = templates.infos().len(),
+        pipelines = templates.pipeline_infos().len(),
+        default = templates.default_name(),
+        "loaded prompt templates"
+    );
 
-    // set model from environment variable
-    let model = env::var("MODEL").unwrap_or_else(|_| "meta-llama/llama-3.2-3b-instruct".to_string());
     // -------------------------------------------------
-    // 2️⃣  Create executor with custom client and specific model
+    // 3️⃣  Shared state
     // -------------------------------------------------
-    let client = Client::with_config(config);
-    let mut options_builder = Options::builder();
-    options_builder.add_option(Opt::Model(Model::Other(model.to_string()).into()));
-    let options = options_builder.build();
-    let executor = Arc::new(Executor::for_client(client, options));
+    // Conversations stay in memory until they have been idle for
+    // `conversations.ttl_secs`; SQLite keeps them after that.
+    let conversations = Arc::new(ConversationStore::new(Duration::from_secs(config.conversations.ttl_secs)));
 
-    // -------------------------------------------------
-    // 3️⃣  Create prompt template - System prompt
-    // -------------------------------------------------
-    let step = Step::for_prompt_template(prompt!(
-        "You are a helpful assistant. Answer concisely:\n{{question}}"
-    ));
+    // API keys are optional: `label=sha256` pairs, or a TOML file that can
+    // also restrict each key's routes and models. Without any key the API
+    // stays open.
+    let mut api_keys = ApiKeys::default();
+    if let Some(path) = &config.auth.keys_path {
+        api_keys = api_keys.load(path).map_err(anyhow::Error::msg)?;
+    }
+    let api_keys = Arc::new(
+        api_keys
+            .parse(&config.auth.keys.join(","))
+            .map_err(anyhow::Error::msg)?,
+    );
 
-    // -------------------------------------------------
-    // 4️⃣  Build the chain
-    // -------------------------------------------------
-    let chain = Chain::of_one(step);
+    // Each client gets a number of requests per route and period.
+    let rate_limits = config.rate_limits().map_err(anyhow::Error::msg)?;
 
-    // -------------------------------------------------
-    // 5️⃣  Set up Actix web server
-    // -------------------------------------------------
-    // Store executor and chain in AppState for efficient sharing.
-    // Even though only /completion uses them, this avoids recreating
-    // these expensive resources on every request.
-    let app_state = AppState {
-        executor: executor.clone(),
-        chain: Arc::new(chain),
+    // Conversations are persisted to SQLite.
+    let storage = Arc::new(Storage::open(&config.database.path)?);
+    info!(path = %config.database.path.display(), "conversation history stored in SQLite");
+
+    // /summarize keeps every prompt under `summarize.prompt_tokens`,
+    // splitting longer text into chunks.
+    let summarizer = match providers.get("openrouter") {
+        Some(openrouter) => Some(Arc::new(Summarizer::new(ProviderExecutor::new(
+            openrouter,
+            config.summarize.prompt_tokens,
+        )?))),
+        None => None,
+    };
+
+    // On timeouts, 429s and 5xx, requests are retried on each fallback model in order.
+    let fallbacks = FallbackChain::new(upstream.fallback_targets().map_err(anyhow::Error::msg)?, &providers)
+        .map_err(anyhow::Error::msg)?;
+    if !fallbacks.targets().is_empty() {
+        info!(count = fallbacks.targets().len(), "fallback models configured");
+    }
+
+    // Repeated questions on /completion and /groqlive are answered from the
+    // response cache, in memory or on disk, until its entries expire.
+    let cache_ttl = Duration::from_secs(config.cache.ttl_secs);
+    let cache = match config.cache.mode {
+        CacheMode::Off => None,
+        CacheMode::Memory => {
+            info!(capacity = config.cache.capacity, "response cache in memory");
+            Some(Arc::new(ResponseCache::new(cache_ttl, MemoryBackend::new(config.cache.capacity))))
+        }
+        CacheMode::Disk => {
+            info!(dir = %config.cache.dir.display(), "response cache on disk");
+            Some(Arc::new(ResponseCache::new(cache_ttl, DiskBackend::open(&config.cache.dir)?)))
+        }
     };
 
-    // Get port from environment variable (Render.com provides PORT)
-    let port = env::var("PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse::<u16>()
-        .unwrap_or(8080);
-    
-    let bind_address = format!("0.0.0.0:{}", port);
+    // Token prices per model turn usage into cost; models without a price
+    // only report tokens.
+    let prices = match &config.prices.path {
+        Some(path) => PriceTable::load(path).map_err(anyhow::Error::msg)?,
+        None => PriceTable::default(),
+    };
+    info!(models = prices.len(), "token prices loaded");
 
-    println!("🚀 Starting server on http://0.0.0.0:{}", port);
-    println!("📡 POST endpoint: http://0.0.0.0:{}/completion", port);
-    println!("🌐 Static files served from /static directory");
+    // /readyz asks each provider for its model list, waiting at most the
+    // readiness timeout and reusing the answer for a while.
+    let probes = ProviderProbes::new(
+        Duration::from_secs(config.readiness.cache_secs),
+        Duration::from_secs(config.readiness.timeout_secs),
+    );
 
-    HttpServer::new(move || {
-        // Configure CORS
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .supports_credentials();
+    Ok(AppState {
+        providers,
+        templates: Arc::new(templates),
+        conversations,
+        storage,
+        summarizer,
+        params: Arc::new(params),
+        fallbacks: Arc::new(fallbacks),
+        cache,
+        prices: Arc::new(prices),
+        probes: Arc::new(probes),
+        api_keys,
+        rate_limits,
+        config: Arc::new(config),
+    })
+}
 
+/// Runs the HTTP server until it is shut down.
+async fn serve(app_state: AppState) -> anyhow::Result<()> {
+    let config = app_state.config.clone();
+    for (name, reason) in app_state.providers.disabled() {
+        warn!(provider = name, reason, "provider disabled");
+    }
+    if app_state.providers.iter().next().is_none() {
+        warn!("no provider has an API key, only the static site will work");
+    }
+    if app_state.api_keys.is_empty() {
+        warn!("no API keys configured, the API is open");
+    } else {
+        info!(count = app_state.api_keys.len(), "API keys configured");
+    }
+
+    // CORS for browsers on other sites; origins may use wildcard subdomains
+    // such as "https://*.example.com".
+    let cors_origins = config.cors.origins.join(", ");
+    let cors_policy = config.cors.clone().build().map_err(anyhow::Error::msg)?;
+    info!(origins = if cors_origins.is_empty() { "none" } else { &cors_origins }, "CORS configured");
+
+    for (route, quota) in app_state.rate_limits.quotas() {
+        info!(route, requests = quota.requests, period_secs = quota.period.as_secs(), "rate limit per client");
+    }
+
+    let sweeper = app_state.conversations.clone();
+    let limits = app_state.rate_limits.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            sweeper.evict_expired();
+            limits.evict_idle();
+        }
+    });
+
+    if let Some(cache) = app_state.cache.clone() {
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let cache = cache.clone();
+                // The disk backend reads every file, so keep it off the runtime.
+                let _ = web::block(move || cache.evict_expired()).await;
+            }
+        });
+    }
+
+    let bind_address = format!("{}:{}", config.server.host, config.server.port);
+    info!(address = %bind_address, "starting server");
+
+    HttpServer::new(move || {
         App::new()
-            .wrap(cors)
-            .wrap(Logger::default())
-            .app_data(web::Data::new(app_state.clone()))
-            .route("/completion", web::post().to(completion))
-            .route("/groqlive", web::post().to(groqlive))
-            // Register /name route BEFORE static files to avoid route conflicts
-            .service(
-                web::scope("/name")
-                    .route("/{nameparam}", web::get().to(greeter_with_name))
-                    .route("", web::get().to(greeter_default))
-            )
-            // Serve static files (images, etc.) from /static path
-            .service(Files::new("/static", "./static"))
-            // Serve index.html and other static files from root
-            .service(
-                Files::new("/", "./static")
-                    .index_file("index.html")
-            )
+            .wrap(cors_policy.cors())
+            .wrap(RequestMetrics)
+            // Outermost, so everything below runs in the request's span.
+            .wrap(RequestTracing)
+            .configure(|cfg| app::configure(cfg, &app_state))
     })
     .bind(&bind_address)?
     .run()
@@ -0,0 +1,405 @@
+//! Prometheus metrics, rendered in the text exposition format at `/metrics`.
+//!
+//! Metrics live in one process-wide registry (`metrics()`), so the provider
+//! clients can record upstream calls without the registry being threaded
+//! through them. HTTP requests are labelled with the matched route pattern
+//! (`/conversations/{id}`, not the id) to keep the number of series bounded.
+//! For the same reason upstream calls and tokens are labelled with the model
+//! only if it is one of the provider's configured models (`label_models`);
+//! anything else, e.g. a model a `*` allowlist let through, counts as `other`.
+
+use crate::providers::Usage;
+use actix_web::body::MessageBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Histogram buckets in seconds, from cache hits up to slow model answers.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The process-wide registry.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Escapes a label value as the exposition format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn label_set(names: &[&str], values: &[String], extra: Option<(&str, &str)>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some((name, value)) = extra {
+        pairs.push(format!("{}=\"{}\"", name, value));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// Counters or gauges sharing a name, one per label combination.
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, f64>>,
+}
+
+impl Family {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        kind: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn add(&self, labels: &[&str], delta: f64) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_default() += delta;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                label_set(self.labels, labels, None),
+                value
+            );
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Buckets {
+    /// Observations per bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Latency histograms sharing a name, one per label combination.
+struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Buckets>>,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, labels: &[&str], elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let buckets = values.entry(key).or_insert_with(|| Buckets {
+            counts: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        });
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            buckets.counts[index] += 1;
+        }
+        buckets.sum += seconds;
+        buckets.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (labels, buckets) in self.values.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&buckets.counts) {
+                cumulative += count;
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    self.name,
+                    label_set(self.labels, labels, Some(("le", &le))),
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                label_set(self.labels, labels, Some(("le", "+Inf"))),
+                buckets.count
+            );
+            let set = label_set(self.labels, labels, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, set, buckets.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, set, buckets.count);
+        }
+    }
+}
+
+pub struct Metrics {
+    http_requests: Family,
+    http_errors: Family,
+    http_duration: Histogram,
+    http_in_flight: Family,
+    upstream_requests: Family,
+    upstream_errors: Family,
+    upstream_duration: Histogram,
+    tokens: Family,
+    cache_lookups: Family,
+    /// Models labelled by name, per provider.
+    models: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            http_requests: Family::new(
+                "rustybot_http_requests_total",
+                "HTTP requests by route, method and status.",
+                "counter",
+                &["route", "method", "status"],
+            ),
+            http_errors: Family::new(
+                "rustybot_http_errors_total",
+                "HTTP requests answered with a 5xx status, by route.",
+                "counter",
+                &["route"],
+            ),
+            http_duration: Histogram::new(
+                "rustybot_http_request_duration_seconds",
+                "Time until the response headers were ready, by route.",
+                &["route"],
+            ),
+            http_in_flight: Family::new(
+                "rustybot_http_requests_in_flight",
+                "HTTP requests being handled, by route.",
+                "gauge",
+                &["route"],
+            ),
+            upstream_requests: Family::new(
+                "rustybot_upstream_requests_total",
+                "Upstream chat calls by provider, model and HTTP status (or timeout/network).",
+                "counter",
+                &["provider", "model", "status"],
+            ),
+            upstream_errors: Family::new(
+                "rustybot_upstream_errors_total",
+                "Upstream chat calls that failed, retries included.",
+                "counter",
+                &["provider", "model"],
+            ),
+            upstream_duration: Histogram::new(
+                "rustybot_upstream_request_duration_seconds",
+                "Time until the upstream answered (or, when streaming, started to).",
+                &["provider", "model"],
+            ),
+            tokens: Family::new(
+                "rustybot_tokens_total",
+                "Tokens used, by provider, model and kind (prompt or completion).",
+                "counter",
+                &["provider", "model", "kind"],
+            ),
+            cache_lookups: Family::new(
+                "rustybot_cache_lookups_total",
+                "Response cache lookups by result (hit or miss).",
+                "counter",
+                &["result"],
+            ),
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn observe_http(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        self.http_requests
+            .add(&[route, method, &status.to_string()], 1.0);
+        if status >= 500 {
+            self.http_errors.add(&[route], 1.0);
+        }
+        self.http_duration.observe(&[route], elapsed);
+    }
+
+    /// Labels `models` on `provider` by name; its other models count as `other`.
+    pub fn label_models(&self, provider: &str, models: Vec<String>) {
+        self.models
+            .lock()
+            .unwrap()
+            .insert(provider.to_string(), models);
+    }
+
+    fn model_label<'a>(&self, provider: &str, model: &'a str) -> &'a str {
+        let models = self.models.lock().unwrap();
+        match models.get(provider) {
+            Some(models) if models.iter().any(|m| m == model) => model,
+            _ => "other",
+        }
+    }
+
+    /// Records one upstream attempt; `status` is the HTTP status or `timeout`/`network`.
+    pub fn observe_upstream(&self, provider: &str, model: &str, status: &str, elapsed: Duration) {
+        let model = self.model_label(provider, model);
+        self.upstream_requests.add(&[provider, model, status], 1.0);
+        if !status.starts_with('2') {
+            self.upstream_errors.add(&[provider, model], 1.0);
+        }
+        self.upstream_duration.observe(&[provider, model], elapsed);
+    }
+
+    pub fn add_tokens(&self, provider: &str, model: &str, usage: &Usage) {
+        let model = self.model_label(provider, model);
+        self.tokens
+            .add(&[provider, model, "prompt"], f64::from(usage.prompt_tokens));
+        self.tokens.add(
+            &[provider, model, "completion"],
+            f64::from(usage.completion_tokens),
+        );
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        self.cache_lookups
+            .add(&[if hit { "hit" } else { "miss" }], 1.0);
+    }
+
+    /// Everything in the text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.http_requests.render(&mut out);
+        self.http_errors.render(&mut out);
+        self.http_duration.render(&mut out);
+        self.http_in_flight.render(&mut out);
+        self.upstream_requests.render(&mut out);
+        self.upstream_errors.render(&mut out);
+        self.upstream_duration.render(&mut out);
+        self.tokens.render(&mut out);
+        self.cache_lookups.render(&mut out);
+
+        let lookups = self.cache_lookups.values.lock().unwrap();
+        let count = |result: &str| {
+            lookups
+                .get(&vec![result.to_string()])
+                .copied()
+                .unwrap_or(0.0)
+        };
+        let (hits, total) = (count("hit"), count("hit") + count("miss"));
+        let _ = writeln!(
+            out,
+            "# HELP rustybot_cache_hit_ratio Share of response cache lookups that were hits."
+        );
+        let _ = writeln!(out, "# TYPE rustybot_cache_hit_ratio gauge");
+        let ratio = if total > 0.0 { hits / total } else { 0.0 };
+        let _ = writeln!(out, "rustybot_cache_hit_ratio {}", ratio);
+        out
+    }
+}
+
+/// Lowers the in-flight gauge when the request is done, however it ends.
+struct InFlight {
+    route: String,
+}
+
+impl InFlight {
+    fn start(route: String) -> Self {
+        metrics().http_in_flight.add(&[&route], 1.0);
+        Self { route }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        metrics().http_in_flight.add(&[&self.route], -1.0);
+    }
+}
+
+/// Middleware recording every request in `metrics()`.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let method = req.method().to_string();
+        let started = Instant::now();
+        let in_flight = InFlight::start(route.clone());
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let result = service.call(req).await;
+            let status = match &result {
+                Ok(response) => response.status().as_u16(),
+                Err(error) => error.as_response_error().status_code().as_u16(),
+            };
+            metrics().observe_http(&route, &method, status, started.elapsed());
+            drop(in_flight);
+            result
+        })
+    }
+}
@@ -0,0 +1,527 @@
+//! OpenAI-compatible facade: `POST /v1/chat/completions` and `GET /v1/models`.
+//!
+//! Clients written for the OpenAI chat API can use this server as their base
+//! URL (`http://host:8080/v1`). Requests go through the same API keys, rate
+//! limits, parameter checks, fallbacks and usage accounting as `/completion`.
+//!
+//! Models are named `provider/model`, e.g. `groq/llama-3.1-8b-instant`; a bare
+//! provider name picks its default model, and ids without a known provider
+//! prefix go to OpenRouter. Ids a provider serves under its own name, like
+//! Groq's `groq/compound-mini`, are not prefixed twice. Besides the standard fields, requests may name
+//! one of our prompt `template`s (with `variables`), which then wraps the last
+//! user message like a `/completion` question.
+//!
+//! Every error on `/v1`, including those of the API key and rate limit
+//! middleware, is rewritten by `error_handlers` into OpenAI's error object,
+//! the same one a stream ends with when the upstream fails midway.
+
+use crate::app::{ask_stream, ask_verbatim, AppState};
+use crate::auth::Principal;
+use crate::params::RequestParams;
+use crate::providers::{
+    ChatMessage, ChatRequest, ChatStream, ProviderError, Role, StreamEvent, Usage,
+};
+use crate::templates;
+use actix_web::body::{self, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError, Result as ActixResult};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Provider for model ids without a provider prefix.
+const DEFAULT_PROVIDER: &str = "openrouter";
+
+#[derive(Deserialize)]
+pub(crate) struct ChatCompletionRequest {
+    /// `provider/model`, a provider name, or an OpenRouter model id.
+    model: Option<String>,
+    messages: Vec<RequestMessage>,
+    #[serde(default)]
+    stream: bool,
+    stream_options: Option<StreamOptions>,
+    tools: Option<Vec<Value>>,
+    tool_choice: Option<Value>,
+    /// Only one choice per request is supported.
+    n: Option<u32>,
+    /// Name of a prompt template for the last user message; not part of the
+    /// OpenAI API.
+    template: Option<String>,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    /// Sampling parameters, checked against `ParamPolicy`. `model` is taken
+    /// by the field above and resolved separately.
+    #[serde(flatten)]
+    params: RequestParams,
+}
+
+#[derive(Deserialize)]
+struct StreamOptions {
+    #[serde(default)]
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct RequestMessage {
+    role: String,
+    content: Option<Content>,
+    tool_calls: Option<Vec<Value>>,
+    tool_call_id: Option<String>,
+}
+
+/// A plain string, or a list of typed parts of which only text is supported.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+struct ContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OpenAiError {
+    NoMessages,
+    UnsupportedRole(String),
+    UnsupportedContent(String),
+    /// `n` asked for more than one choice.
+    Choices(u32),
+    /// A template was named but the last message is not the user's.
+    NoQuestion,
+}
+
+impl fmt::Display for OpenAiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenAiError::NoMessages => write!(f, "messages must not be empty"),
+            OpenAiError::UnsupportedRole(role) => write!(f, "Unsupported message role '{}'", role),
+            OpenAiError::UnsupportedContent(kind) => {
+                write!(f, "Only text content is supported, not '{}'", kind)
+            }
+            OpenAiError::Choices(n) => write!(f, "Only n=1 is supported, not {}", n),
+            OpenAiError::NoQuestion => {
+                write!(f, "A template needs the last message to be a user message")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OpenAiError {}
+
+impl ResponseError for OpenAiError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let param = match self {
+            OpenAiError::Choices(_) => "n",
+            _ => "messages",
+        };
+        HttpResponse::build(self.status_code()).json(error_object(
+            self.status_code(),
+            self.to_string(),
+            Some(param),
+        ))
+    }
+}
+
+/// OpenAI's error body: `{"error": {"message", "type", "param", "code"}}`.
+fn error_object(status: StatusCode, message: String, param: Option<&str>) -> Value {
+    let (kind, code) = match status {
+        StatusCode::UNAUTHORIZED => ("authentication_error", Some("invalid_api_key")),
+        StatusCode::FORBIDDEN => ("permission_error", None),
+        StatusCode::NOT_FOUND => ("not_found_error", None),
+        StatusCode::TOO_MANY_REQUESTS => ("rate_limit_error", Some("rate_limit_exceeded")),
+        status if status.is_server_error() => ("server_error", None),
+        _ => ("invalid_request_error", None),
+    };
+    json!({
+        "error": {
+            "message": message,
+            "type": kind,
+            "param": param,
+            "code": code,
+        }
+    })
+}
+
+/// The message of an error body: the upstream's own message when it relayed
+/// an OpenAI-style error, otherwise the text itself.
+fn error_message(text: &str) -> String {
+    serde_json::from_str::<Value>(text)
+        .ok()
+        .and_then(|body| body["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| text.to_string())
+}
+
+/// Middleware rewriting the server's `{"error": "..."}` bodies (and any other
+/// error response) into `error_object`s, keeping status and headers.
+pub fn error_handlers<B: MessageBody + 'static>() -> ErrorHandlers<B> {
+    ErrorHandlers::new().default_handler(to_error_object)
+}
+
+fn to_error_object<B: MessageBody + 'static>(
+    response: ServiceResponse<B>,
+) -> ActixResult<ErrorHandlerResponse<B>> {
+    let (req, response) = response.into_parts();
+    let status = response.status();
+    let (mut head, body) = response.into_parts();
+    Ok(ErrorHandlerResponse::Future(Box::pin(async move {
+        let bytes = body::to_bytes(body).await.ok().unwrap_or_default();
+        let error = match serde_json::from_slice::<Value>(&bytes) {
+            // Already in OpenAI's form, e.g. from `OpenAiError`.
+            Ok(body) if body["error"]["type"].is_string() => body,
+            Ok(Value::Object(mut fields)) => {
+                let message = match fields.remove("error") {
+                    Some(Value::String(message)) => error_message(&message),
+                    Some(error) if error["message"].is_string() => {
+                        error["message"].as_str().unwrap().to_string()
+                    }
+                    _ => status.canonical_reason().unwrap_or_default().to_string(),
+                };
+                let mut error = error_object(status, message, None);
+                // Keep extras such as `failed_attempts` next to the error.
+                error.as_object_mut().unwrap().extend(fields);
+                error
+            }
+            _ => {
+                let text = String::from_utf8_lossy(&bytes).trim().to_string();
+                let message = if text.is_empty() {
+                    status.canonical_reason().unwrap_or_default().to_string()
+                } else {
+                    text
+                };
+                error_object(status, message, None)
+            }
+        };
+        head.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        let response = head.set_body(error.to_string());
+        Ok(ServiceResponse::new(req, response)
+            .map_into_boxed_body()
+            .map_into_right_body())
+    })))
+}
+
+impl RequestMessage {
+    fn into_chat(self) -> Result<ChatMessage, OpenAiError> {
+        let role = match self.role.as_str() {
+            // Newer OpenAI clients send system prompts as `developer`.
+            "system" | "developer" => Role::System,
+            "user" => Role::User,
+            "assistant" => Role::Assistant,
+            "tool" => Role::Tool,
+            _ => return Err(OpenAiError::UnsupportedRole(self.role)),
+        };
+        let content = match self.content {
+            None => String::new(),
+            Some(Content::Text(text)) => text,
+            Some(Content::Parts(parts)) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    match (part.kind.as_str(), part.text) {
+                        ("text", Some(text)) => texts.push(text),
+                        _ => return Err(OpenAiError::UnsupportedContent(part.kind)),
+                    }
+                }
+                texts.join("\n")
+            }
+        };
+        Ok(ChatMessage {
+            tool_calls: self.tool_calls.unwrap_or_default(),
+            tool_call_id: self.tool_call_id,
+            ..ChatMessage::new(role, content)
+        })
+    }
+}
+
+/// The provider `model` names and the model to ask it for, `None` meaning
+/// its default.
+fn route_model<'a>(state: &AppState, model: Option<&'a str>) -> (&'a str, Option<String>) {
+    let Some(model) = model.map(str::trim).filter(|model| !model.is_empty()) else {
+        return (DEFAULT_PROVIDER, None);
+    };
+    if state.providers.is_known(model) {
+        return (model, None);
+    }
+    // Model ids may themselves look prefixed, like Groq's "groq/compound-mini".
+    if let Some(provider) = state.providers.iter().find(|provider| {
+        state
+            .params
+            .models(provider.as_ref())
+            .iter()
+            .any(|m| m == model)
+    }) {
+        return (provider.name(), Some(model.to_string()));
+    }
+    match model.split_once('/') {
+        Some((provider, model)) if state.providers.is_known(provider) => {
+            (provider, Some(model.to_string()))
+        }
+        _ => (DEFAULT_PROVIDER, Some(model.to_string())),
+    }
+}
+
+/// Converts the request's messages. With a template, the last one is
+/// rendered through it and the others go before it as history.
+fn chat_messages(
+    state: &AppState,
+    messages: Vec<RequestMessage>,
+    template: Option<&str>,
+    variables: &HashMap<String, String>,
+) -> ActixResult<Vec<ChatMessage>> {
+    let mut messages = messages
+        .into_iter()
+        .map(RequestMessage::into_chat)
+        .collect::<Result<Vec<_>, _>>()?;
+    if messages.is_empty() {
+        return Err(OpenAiError::NoMessages.into());
+    }
+    let Some(template) = template else {
+        return Ok(messages);
+    };
+    let question = messages
+        .pop()
+        .filter(|message| message.role == Role::User)
+        .ok_or(OpenAiError::NoQuestion)?;
+    let rendered = state
+        .templates
+        .get(Some(template))?
+        .render(&question.content, variables)?;
+    Ok(templates::with_history(rendered, messages))
+}
+
+pub(crate) async fn chat_completions(
+    req: web::Json<ChatCompletionRequest>,
+    state: web::Data<AppState>,
+    principal: Principal,
+) -> ActixResult<HttpResponse> {
+    let req = req.into_inner();
+    if let Some(n) = req.n.filter(|&n| n != 1) {
+        return Err(OpenAiError::Choices(n).into());
+    }
+    let (provider, model) = route_model(&state, req.model.as_deref());
+    let provider = state.provider(provider)?;
+    let params = RequestParams {
+        model,
+        ..req.params
+    };
+    let mut options = state.check_params(&principal, provider.as_ref(), &params)?;
+    options.tools = req.tools;
+    options.tool_choice = req.tool_choice;
+    let messages = chat_messages(
+        &state,
+        req.messages,
+        req.template.as_deref(),
+        &req.variables,
+    )?;
+    let request = ChatRequest::new(messages).with_options(options);
+
+    let id = format!("chatcmpl-{}", uuid::Uuid::new_v4().simple());
+    let created = unix_now();
+
+    if req.stream {
+        // Errors before the first byte still surface as a normal JSON error.
+        let answered = ask_stream(&state, &principal, provider, request).await?;
+        let model = model_id(answered.provider, &answered.model);
+        let include_usage = req
+            .stream_options
+            .is_some_and(|options| options.include_usage);
+        let state = state.into_inner();
+        let (provider, upstream_model) = (answered.provider, answered.model);
+        return Ok(stream_response(
+            Chunks {
+                id,
+                created,
+                model,
+                include_usage,
+                started: false,
+            },
+            answered.value,
+            move |usage| {
+                state.account(&principal, provider, &upstream_model, usage);
+            },
+        ));
+    }
+
+    let answered = ask_verbatim(&state, &principal, provider, request).await?;
+    let response = answered.value;
+    state.account(
+        &principal,
+        answered.provider,
+        &answered.model,
+        response.usage,
+    );
+
+    let mut message = json!({ "role": "assistant", "content": response.content });
+    if !response.tool_calls.is_empty() {
+        if response.content.is_empty() {
+            message["content"] = Value::Null;
+        }
+        message["tool_calls"] = json!(response.tool_calls);
+    }
+    let finish_reason = response.finish_reason.unwrap_or_else(|| "stop".to_string());
+    Ok(HttpResponse::Ok().json(json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model_id(answered.provider, &answered.model),
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": finish_reason,
+        }],
+        "usage": response.usage,
+    })))
+}
+
+/// Builds the `chat.completion.chunk` events of one streamed answer.
+struct Chunks {
+    id: String,
+    created: u64,
+    model: String,
+    include_usage: bool,
+    /// Whether the first chunk, which carries the role, went out.
+    started: bool,
+}
+
+impl Chunks {
+    fn chunk(&self, choices: Value, usage: Option<Usage>) -> String {
+        let mut chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        });
+        if self.include_usage {
+            chunk["usage"] = json!(usage);
+        }
+        data(&chunk)
+    }
+
+    fn delta(&mut self, mut delta: Value) -> String {
+        if !self.started {
+            self.started = true;
+            delta["role"] = json!("assistant");
+        }
+        self.chunk(
+            json!([{ "index": 0, "delta": delta, "finish_reason": null }]),
+            None,
+        )
+    }
+
+    fn done(&mut self, finish_reason: Option<String>, usage: Option<Usage>) -> String {
+        let finish_reason = finish_reason.unwrap_or_else(|| "stop".to_string());
+        let mut frames = self.chunk(
+            json!([{ "index": 0, "delta": {}, "finish_reason": finish_reason }]),
+            None,
+        );
+        if self.include_usage {
+            frames.push_str(&self.chunk(json!([]), usage));
+        }
+        frames.push_str("data: [DONE]\n\n");
+        frames
+    }
+}
+
+fn data(value: &Value) -> String {
+    format!("data: {}\n\n", value)
+}
+
+/// Relays `stream` in the OpenAI streaming format, calling `on_done` with
+/// the token usage once the upstream finishes.
+fn stream_response<F>(mut chunks: Chunks, stream: ChatStream, on_done: F) -> HttpResponse
+where
+    F: FnOnce(Option<Usage>) + 'static,
+{
+    let mut on_done = Some(on_done);
+    let body = stream.map(move |item| {
+        let frames = match item {
+            Ok(StreamEvent::Delta(content)) => chunks.delta(json!({ "content": content })),
+            Ok(StreamEvent::ToolCalls(calls)) => chunks.delta(json!({ "tool_calls": calls })),
+            Ok(StreamEvent::Done {
+                finish_reason,
+                usage,
+            }) => {
+                if let Some(on_done) = on_done.take() {
+                    on_done(usage);
+                }
+                chunks.done(finish_reason, usage)
+            }
+            Err(e) => {
+                let message = match &e {
+                    ProviderError::Upstream { body, .. } => error_message(body),
+                    other => other.to_string(),
+                };
+                data(&error_object(e.status_code(), message, None))
+            }
+        };
+        Ok::<_, actix_web::Error>(Bytes::from(frames))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // Stop reverse proxies (Render, nginx) from buffering the stream.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body)
+}
+
+/// `provider/model`, or just `model` when it already starts with the
+/// provider's name, as `route_model` then sends it to that provider.
+fn model_id(provider: &str, model: &str) -> String {
+    match model.strip_prefix(provider) {
+        Some(rest) if rest.starts_with('/') => model.to_string(),
+        _ => format!("{}/{}", provider, model),
+    }
+}
+
+/// Seconds since the Unix epoch, for `created`.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// The models `/v1/chat/completions` accepts, named by `model_id`: each
+/// enabled provider's default model and its allowed models, less those the
+/// caller's API key may not use.
+pub(crate) async fn models(state: web::Data<AppState>, principal: Principal) -> HttpResponse {
+    let created = unix_now();
+    let data: Vec<Value> = state
+        .providers
+        .iter()
+        .flat_map(|provider| {
+            state
+                .params
+                .models(provider.as_ref())
+                .into_iter()
+                .filter(|model| principal.check_model(model).is_ok())
+                .map(move |model| {
+                    json!({
+                        "id": model_id(provider.name(), &model),
+                        "object": "model",
+                        "created": created,
+                        "owned_by": provider.name(),
+                    })
+                })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "object": "list", "data": data }))
+}
@@ -0,0 +1,207 @@
+//! Per-request model and sampling parameters.
+//!
+//! Requests may pick a model and tune sampling, but only within what the
+//! server allows: each provider has an allowlist of models (its default model
+//! is always allowed) and every numeric parameter has a fixed range.
+//! `ParamPolicy::check` turns the raw request fields into `ChatOptions`.
+
+use crate::providers::{ChatOptions, LlmProvider};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Most stop sequences OpenAI-compatible APIs accept.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// The optional fields a request body may carry, as sent by the client.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RequestParams {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub top_p: Option<f64>,
+    pub stop: Option<StopSequences>,
+    pub seed: Option<u64>,
+}
+
+/// `stop` may be a single string or a list, as in the OpenAI API.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StopSequences {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            StopSequences::One(stop) => vec![stop],
+            StopSequences::Many(stops) => stops,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamsError {
+    ModelNotAllowed {
+        provider: String,
+        model: String,
+        allowed: Vec<String>,
+    },
+    OutOfRange {
+        name: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamsError::ModelNotAllowed {
+                provider,
+                model,
+                allowed,
+            } => write!(
+                f,
+                "Model '{}' is not allowed for {} (allowed: {})",
+                model,
+                provider,
+                allowed.join(", ")
+            ),
+            ParamsError::OutOfRange { name, reason } => {
+                write!(f, "Invalid {}: {}", name, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParamsError {}
+
+impl ResponseError for ParamsError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Which models each provider may be asked for, and the `max_tokens` ceiling.
+#[derive(Clone, Debug)]
+pub struct ParamPolicy {
+    /// Extra models per provider name; `"*"` allows any model.
+    allowed_models: HashMap<String, Vec<String>>,
+    max_tokens: u32,
+}
+
+impl ParamPolicy {
+    pub fn new(max_tokens: u32) -> Self {
+        Self {
+            allowed_models: HashMap::new(),
+            max_tokens,
+        }
+    }
+
+    /// Allows `models` on `provider` in addition to its default model.
+    pub fn allow_models(
+        mut self,
+        provider: &str,
+        models: impl IntoIterator<Item = String>,
+    ) -> Self {
+        self.allowed_models
+            .entry(provider.to_string())
+            .or_default()
+            .extend(models);
+        self
+    }
+
+    /// The models requests may pick on `provider`: its default model, then the
+    /// allowed ones. A `"*"` entry is left out.
+    pub fn models(&self, provider: &dyn LlmProvider) -> Vec<String> {
+        let mut models = vec![provider.default_model().to_string()];
+        if let Some(extra) = self.allowed_models.get(provider.name()) {
+            for model in extra {
+                if model != "*" && !models.contains(model) {
+                    models.push(model.clone());
+                }
+            }
+        }
+        models
+    }
+
+    /// Validates `params` for `provider` and converts them into request options.
+    pub fn check(
+        &self,
+        provider: &dyn LlmProvider,
+        params: &RequestParams,
+    ) -> Result<ChatOptions, ParamsError> {
+        if let Some(model) = &params.model {
+            self.check_model(provider, model)?;
+        }
+        if let Some(temperature) = params.temperature {
+            in_range("temperature", temperature, 0.0, 2.0)?;
+        }
+        if let Some(top_p) = params.top_p {
+            in_range("top_p", top_p, 0.0, 1.0)?;
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            if max_tokens == 0 || max_tokens > self.max_tokens {
+                return Err(ParamsError::OutOfRange {
+                    name: "max_tokens",
+                    reason: format!("must be between 1 and {}", self.max_tokens),
+                });
+            }
+        }
+        let stop = params.stop.clone().map(StopSequences::into_vec);
+        if let Some(stop) = &stop {
+            if stop.len() > MAX_STOP_SEQUENCES || stop.iter().any(String::is_empty) {
+                return Err(ParamsError::OutOfRange {
+                    name: "stop",
+                    reason: format!("use at most {} non-empty sequences", MAX_STOP_SEQUENCES),
+                });
+            }
+        }
+
+        Ok(ChatOptions {
+            model: params.model.clone(),
+            temperature: params.temperature,
+            max_tokens: params.max_tokens,
+            top_p: params.top_p,
+            stop,
+            seed: params.seed,
+            ..ChatOptions::default()
+        })
+    }
+
+    pub fn check_model(&self, provider: &dyn LlmProvider, model: &str) -> Result<(), ParamsError> {
+        let extra = self
+            .allowed_models
+            .get(provider.name())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if model == provider.default_model() || extra.iter().any(|m| m == "*" || m == model) {
+            return Ok(());
+        }
+        let mut allowed = vec![provider.default_model().to_string()];
+        allowed.extend(extra.iter().cloned());
+        Err(ParamsError::ModelNotAllowed {
+            provider: provider.name().to_string(),
+            model: model.to_string(),
+            allowed,
+        })
+    }
+}
+
+fn in_range(name: &'static str, value: f64, min: f64, max: f64) -> Result<(), ParamsError> {
+    if (min..=max).contains(&value) {
+        Ok(())
+    } else {
+        Err(ParamsError::OutOfRange {
+            name,
+            reason: format!("must be between {} and {}", min, max),
+        })
+    }
+}
@@ -0,0 +1,424 @@
+//! A provider that answers locally, for offline development and tests.
+//!
+//! By default it echoes the last user message. A script (TOML) can give
+//! canned replies for questions containing some text, add latency, and make
+//! the provider fail the way real upstreams do:
+//!
+//! ```toml
+//! latency_ms = 200       # before the answer or the first chunk
+//! chunk_delay_ms = 20    # between streamed chunks
+//!
+//! [[rules]]
+//! contains = "weather"
+//! reply = "Sunny, 24°C."
+//!
+//! [[rules]]
+//! contains = "busy"
+//! status = 429
+//!
+//! [[rules]]
+//! contains = "garbage"
+//! malformed = true
+//! ```
+//!
+//! Without a script, questions can ask for the same behaviour inline:
+//! `mock:429` (or any status from 400 to 599), `mock:malformed` and
+//! `mock:latency=1500`.
+
+use super::{
+    ChatRequest, ChatResponse, ChatStream, LlmProvider, ProviderError, Role, StreamEvent, Usage,
+};
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// One scripted answer, chosen when the last user message contains `contains`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockRule {
+    /// Matched case-insensitively.
+    pub contains: String,
+    pub reply: Option<String>,
+    /// Fail with this HTTP status instead of answering.
+    pub status: Option<u16>,
+    /// Fail as if the upstream sent a payload without choices.
+    #[serde(default)]
+    pub malformed: bool,
+    /// Overrides the script's `latency_ms`.
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockScript {
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub chunk_delay_ms: u64,
+    #[serde(default)]
+    pub rules: Vec<MockRule>,
+}
+
+/// What the mock does with one request.
+#[derive(Clone, Debug, PartialEq)]
+enum Outcome {
+    Reply(String),
+    Status(StatusCode),
+    Malformed,
+}
+
+impl MockScript {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::from_toml_str(&contents)
+            .map_err(|e| format!("Invalid mock script {}: {}", path.display(), e))
+    }
+
+    pub fn from_toml_str(contents: &str) -> Result<Self, String> {
+        let script: MockScript = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for rule in &script.rules {
+            let outcomes = [rule.reply.is_some(), rule.status.is_some(), rule.malformed];
+            if outcomes.iter().filter(|set| **set).count() != 1 {
+                return Err(format!(
+                    "rule for '{}' needs exactly one of reply, status or malformed",
+                    rule.contains
+                ));
+            }
+            if let Some(status) = rule.status {
+                if !(400..=599).contains(&status) {
+                    return Err(format!(
+                        "rule for '{}' has status {}, expected 400-599",
+                        rule.contains, status
+                    ));
+                }
+            }
+        }
+        Ok(script)
+    }
+
+    /// The outcome and latency for `question`: the first matching rule, an
+    /// inline `mock:` directive, or an echo.
+    fn plan(&self, question: &str) -> (Outcome, Duration) {
+        let lowered = question.to_lowercase();
+        let mut latency = Duration::from_millis(self.latency_ms);
+        if let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| lowered.contains(&rule.contains.to_lowercase()))
+        {
+            if let Some(ms) = rule.latency_ms {
+                latency = Duration::from_millis(ms);
+            }
+            let outcome = match (&rule.reply, rule.status) {
+                (Some(reply), _) => Outcome::Reply(reply.clone()),
+                (None, Some(status)) => {
+                    Outcome::Status(StatusCode::from_u16(status).expect("validated when loaded"))
+                }
+                (None, None) => Outcome::Malformed,
+            };
+            return (outcome, latency);
+        }
+
+        let mut outcome = Outcome::Reply(format!("Echo: {}", question));
+        for directive in lowered
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix("mock:"))
+        {
+            if directive == "malformed" {
+                outcome = Outcome::Malformed;
+            } else if let Some(ms) = directive.strip_prefix("latency=") {
+                if let Ok(ms) = ms.parse() {
+                    latency = Duration::from_millis(ms);
+                }
+            } else if let Some(status) = directive
+                .parse::<u16>()
+                .ok()
+                .filter(|status| (400..=599).contains(status))
+                .and_then(|status| StatusCode::from_u16(status).ok())
+            {
+                outcome = Outcome::Status(status);
+            }
+        }
+        (outcome, latency)
+    }
+}
+
+/// Stands in for a real provider under its name.
+pub struct MockProvider {
+    name: &'static str,
+    default_model: String,
+    script: Arc<MockScript>,
+}
+
+impl MockProvider {
+    pub fn new(
+        name: &'static str,
+        default_model: impl Into<String>,
+        script: Arc<MockScript>,
+    ) -> Self {
+        Self {
+            name,
+            default_model: default_model.into(),
+            script,
+        }
+    }
+
+    fn model(&self, request: &ChatRequest) -> String {
+        request
+            .options
+            .model
+            .clone()
+            .unwrap_or_else(|| self.default_model.clone())
+    }
+
+    /// Runs the script for `request` after its latency.
+    async fn outcome(&self, request: &ChatRequest) -> Outcome {
+        let question = request
+            .messages
+            .iter()
+            .rev()
+            .find(|m| m.role == Role::User)
+            .map(|m| m.content.as_str())
+            .unwrap_or_default();
+        let (outcome, latency) = self.script.plan(question);
+        tokio::time::sleep(latency).await;
+        outcome
+    }
+}
+
+/// Counts words, which is close enough to tokens for a mock.
+fn usage(request: &ChatRequest, reply: &str) -> Usage {
+    let prompt_tokens = request
+        .messages
+        .iter()
+        .map(|m| m.content.split_whitespace().count() as u32)
+        .sum();
+    let completion_tokens = reply.split_whitespace().count() as u32;
+    Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+fn upstream_error(status: StatusCode) -> ProviderError {
+    ProviderError::Upstream {
+        status,
+        body: json!({
+            "error": {
+                "message": format!("Mock upstream answered {}", status),
+                "code": status.as_u16(),
+            }
+        })
+        .to_string(),
+    }
+}
+
+fn malformed() -> ProviderError {
+    ProviderError::InvalidResponse("no choices in response".to_string())
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<ChatResponse, ProviderError> {
+        match self.outcome(&request).await {
+            Outcome::Reply(reply) => Ok(ChatResponse {
+                usage: Some(usage(&request, &reply)),
+                content: reply,
+                tool_calls: Vec::new(),
+                model: self.model(&request),
+                finish_reason: Some("stop".to_string()),
+            }),
+            Outcome::Status(status) => Err(upstream_error(status)),
+            Outcome::Malformed => Err(malformed()),
+        }
+    }
+
+    async fn chat_stream(&self, request: ChatRequest) -> Result<ChatStream, ProviderError> {
+        let mut events: VecDeque<Result<StreamEvent, ProviderError>> = VecDeque::new();
+        match self.outcome(&request).await {
+            Outcome::Reply(reply) => {
+                events.extend(
+                    reply
+                        .split_inclusive(' ')
+                        .map(|word| Ok(StreamEvent::Delta(word.to_string()))),
+                );
+                events.push_back(Ok(StreamEvent::Done {
+                    finish_reason: Some("stop".to_string()),
+                    usage: Some(usage(&request, &reply)),
+                }));
+            }
+            Outcome::Status(status) => return Err(upstream_error(status)),
+            // Starts fine, then breaks off like a corrupted chunk would.
+            Outcome::Malformed => {
+                events.push_back(Ok(StreamEvent::Delta("Partial ".to_string())));
+                events.push_back(Err(ProviderError::InvalidResponse(
+                    "malformed stream chunk".to_string(),
+                )));
+            }
+        }
+
+        let delay = Duration::from_millis(self.script.chunk_delay_ms);
+        let stream = stream::unfold((events, true), move |(mut events, first)| async move {
+            let event = events.pop_front()?;
+            if !first {
+                tokio::time::sleep(delay).await;
+            }
+            Some((event, (events, false)))
+        });
+        Ok(stream.boxed())
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        Ok(vec![self.default_model.clone()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::ChatMessage;
+    use std::time::Instant;
+
+    fn ask(question: &str) -> ChatRequest {
+        ChatRequest::new(vec![ChatMessage::new(Role::User, question)])
+    }
+
+    fn mock(script: MockScript) -> MockProvider {
+        MockProvider::new("openrouter", "mock-model", Arc::new(script))
+    }
+
+    #[test]
+    fn inline_directives_pick_the_outcome_and_latency() {
+        let script = MockScript::default();
+        assert_eq!(
+            script.plan("Hi"),
+            (Outcome::Reply("Echo: Hi".to_string()), Duration::ZERO)
+        );
+        assert_eq!(
+            script.plan("please MOCK:429").0,
+            Outcome::Status(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(script.plan("mock:malformed").0, Outcome::Malformed);
+        assert_eq!(
+            script.plan("mock:latency=1500 hi").1,
+            Duration::from_millis(1500)
+        );
+        // Out of range or unparsable directives are ignored.
+        for question in ["mock:200", "mock:latency=soon", "mock:teapot"] {
+            assert!(
+                matches!(script.plan(question).0, Outcome::Reply(_)),
+                "{}",
+                question
+            );
+        }
+    }
+
+    #[test]
+    fn scripts_match_rules_before_directives() {
+        let script = MockScript::from_toml_str(
+            r#"
+            latency_ms = 50
+
+            [[rules]]
+            contains = "Weather"
+            reply = "Sunny."
+
+            [[rules]]
+            contains = "busy"
+            status = 503
+            latency_ms = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            script.plan("the weather mock:429"),
+            (
+                Outcome::Reply("Sunny.".to_string()),
+                Duration::from_millis(50)
+            )
+        );
+        assert_eq!(
+            script.plan("busy?"),
+            (
+                Outcome::Status(StatusCode::SERVICE_UNAVAILABLE),
+                Duration::from_millis(5)
+            )
+        );
+
+        for (rule, error) in [
+            ("contains = \"a\"", "needs exactly one of"),
+            (
+                "contains = \"a\"\nreply = \"b\"\nmalformed = true",
+                "needs exactly one of",
+            ),
+            ("contains = \"a\"\nstatus = 200", "expected 400-599"),
+        ] {
+            let error_text =
+                MockScript::from_toml_str(&format!("[[rules]]\n{}", rule)).unwrap_err();
+            assert!(error_text.contains(error), "{}: {}", rule, error_text);
+        }
+    }
+
+    #[actix_web::test]
+    async fn failures_look_like_upstream_failures() {
+        let provider = mock(MockScript::default());
+        match provider.chat(ask("mock:429")).await {
+            Err(ProviderError::Upstream { status, .. }) => {
+                assert_eq!(status, StatusCode::TOO_MANY_REQUESTS)
+            }
+            other => panic!("expected a 429, got {:?}", other.map(|r| r.content)),
+        }
+        assert!(matches!(
+            provider.chat(ask("mock:malformed")).await,
+            Err(ProviderError::InvalidResponse(_))
+        ));
+
+        // Malformed streams start, then break off.
+        let events: Vec<_> = provider
+            .chat_stream(ask("mock:malformed"))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert!(matches!(&events[0], Ok(StreamEvent::Delta(text)) if text == "Partial "));
+        assert!(matches!(events[1], Err(ProviderError::InvalidResponse(_))));
+    }
+
+    #[actix_web::test]
+    async fn answers_wait_for_the_latency_and_report_usage() {
+        let provider = mock(MockScript::default());
+        let started = Instant::now();
+        let response = provider.chat(ask("mock:latency=100 hello")).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(response.content, "Echo: mock:latency=100 hello");
+        assert_eq!(response.model, "mock-model");
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (2, 3));
+
+        let events: Vec<_> = provider
+            .chat_stream(ask("two words"))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(events.len(), 4, "three words, then done");
+        assert!(matches!(events[3], Ok(StreamEvent::Done { .. })));
+    }
+}
@@ -0,0 +1,364 @@
+//! Chat providers behind a single `LlmProvider` trait.
+//!
+//! Every backend we talk to (OpenRouter, Groq, ...) speaks some flavour of the
+//! OpenAI chat API. Handlers only deal with the types in this module, so adding
+//! a backend means adding an implementation here and registering it in `main`.
+
+mod groq;
+mod mock;
+mod openai_compat;
+mod openrouter;
+mod retry;
+
+pub use groq::GroqProvider;
+pub use mock::{MockProvider, MockRule, MockScript};
+pub use openrouter::OpenRouterProvider;
+pub use retry::RetryPolicy;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// The result of a tool call, answering the assistant's `tool_calls`.
+    Tool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+    /// Tools an assistant message asked to call, as the upstream sent them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<Value>,
+    /// The call a `tool` message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+}
+
+impl From<&llm_chain::prompt::ChatMessage<String>> for ChatMessage {
+    fn from(message: &llm_chain::prompt::ChatMessage<String>) -> Self {
+        use llm_chain::prompt::ChatRole;
+        let role = match message.role() {
+            ChatRole::System => Role::System,
+            ChatRole::Assistant => Role::Assistant,
+            // OpenAI-style APIs have no custom roles; treat them as user input.
+            ChatRole::User | ChatRole::Other(_) => Role::User,
+        };
+        Self::new(role, message.body().clone())
+    }
+}
+
+/// Model and sampling settings for one request; `None` leaves the choice to
+/// the provider. Everything but `model` is sent upstream under its own name.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ChatOptions {
+    #[serde(skip)]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Function definitions the model may call, in the OpenAI format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<Value>,
+}
+
+/// A provider-agnostic chat request.
+#[derive(Clone, Debug, Default)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    pub options: ChatOptions,
+}
+
+impl ChatRequest {
+    pub fn new(messages: Vec<ChatMessage>) -> Self {
+        Self {
+            messages,
+            options: ChatOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: ChatOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChatResponse {
+    pub content: String,
+    /// Tool calls the model made instead of, or besides, answering.
+    pub tool_calls: Vec<Value>,
+    pub model: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+}
+
+/// One item of a streamed chat completion.
+#[derive(Clone, Debug)]
+pub enum StreamEvent {
+    /// A chunk of assistant text.
+    Delta(String),
+    /// Fragments of tool calls, as the upstream sent them in `delta.tool_calls`.
+    ToolCalls(Vec<Value>),
+    /// The upstream stream finished.
+    Done {
+        finish_reason: Option<String>,
+        usage: Option<Usage>,
+    },
+}
+
+pub type ChatStream = BoxStream<'static, Result<StreamEvent, ProviderError>>;
+
+#[derive(Debug)]
+pub enum ProviderError {
+    /// The HTTP request could not be sent or the body could not be read.
+    Request(reqwest::Error),
+    /// The upstream did not answer within the configured timeout.
+    Timeout,
+    /// The upstream answered with a non-success status.
+    Upstream { status: StatusCode, body: String },
+    /// The upstream answered 2xx but the payload was not what we expected.
+    InvalidResponse(String),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::Request(e) => write!(f, "Request failed: {}", e),
+            ProviderError::Timeout => write!(f, "Upstream timed out"),
+            ProviderError::Upstream { status, body } => {
+                write!(f, "Upstream returned {}: {}", status, body)
+            }
+            ProviderError::InvalidResponse(msg) => write!(f, "Failed to parse response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            ProviderError::Timeout
+        } else {
+            ProviderError::Request(e)
+        }
+    }
+}
+
+impl ResponseError for ProviderError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            // Pass upstream errors through so clients can tell a 429 from a 500.
+            ProviderError::Upstream { status, .. } => *status,
+            ProviderError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let message = match self {
+            ProviderError::Upstream { body, .. } => body.clone(),
+            other => other.to_string(),
+        };
+        HttpResponse::build(self.status_code()).json(json!({ "error": message }))
+    }
+}
+
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Stable identifier used in configuration and responses, e.g. `"groq"`.
+    fn name(&self) -> &'static str;
+
+    /// Model used when a request does not ask for one.
+    fn default_model(&self) -> &str;
+
+    /// Sends a chat request and waits for the full answer.
+    async fn chat(&self, request: ChatRequest) -> Result<ChatResponse, ProviderError>;
+
+    /// Sends a chat request and yields the answer as it is generated.
+    async fn chat_stream(&self, request: ChatRequest) -> Result<ChatStream, ProviderError>;
+
+    /// Lists the model ids this provider currently serves.
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError>;
+}
+
+/// A provider that cannot take requests.
+#[derive(Debug)]
+pub enum ProviderUnavailable {
+    /// Known, but not configured; `reason` says what is missing.
+    Disabled {
+        name: String,
+        reason: String,
+    },
+    Unknown(String),
+}
+
+impl fmt::Display for ProviderUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderUnavailable::Disabled { name, reason } => {
+                write!(f, "Provider {} is disabled: {}", name, reason)
+            }
+            ProviderUnavailable::Unknown(name) => write!(f, "Unknown provider '{}'", name),
+        }
+    }
+}
+
+impl std::error::Error for ProviderUnavailable {}
+
+impl ResponseError for ProviderUnavailable {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ProviderUnavailable::Disabled { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ProviderUnavailable::Unknown(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// What `/providers` lists about a provider.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProviderInfo {
+    pub name: &'static str,
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_model: Option<String>,
+    /// Why a disabled provider is disabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The set of providers the server was started with, keyed by `LlmProvider::name`.
+///
+/// Providers that are not configured are recorded as disabled, so requests
+/// for them can say why they fail.
+#[derive(Clone, Default)]
+pub struct ProviderRegistry {
+    providers: BTreeMap<&'static str, Arc<dyn LlmProvider>>,
+    disabled: BTreeMap<&'static str, String>,
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, provider: Arc<dyn LlmProvider>) {
+        self.disabled.remove(provider.name());
+        self.providers.insert(provider.name(), provider);
+    }
+
+    /// Records that `name` exists but was left out, e.g. for lack of an API key.
+    pub fn disable(&mut self, name: &'static str, reason: impl Into<String>) {
+        self.providers.remove(name);
+        self.disabled.insert(name, reason.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn LlmProvider>> {
+        self.providers.get(name).cloned()
+    }
+
+    /// Like `get`, but says why a provider cannot be used.
+    pub fn lookup(&self, name: &str) -> Result<Arc<dyn LlmProvider>, ProviderUnavailable> {
+        if let Some(provider) = self.get(name) {
+            return Ok(provider);
+        }
+        Err(match self.disabled.get(name) {
+            Some(reason) => ProviderUnavailable::Disabled {
+                name: name.to_string(),
+                reason: reason.clone(),
+            },
+            None => ProviderUnavailable::Unknown(name.to_string()),
+        })
+    }
+
+    /// Whether `name` is registered or disabled.
+    pub fn is_known(&self, name: &str) -> bool {
+        self.providers.contains_key(name) || self.disabled.contains_key(name)
+    }
+
+    /// Every registered provider, by name.
+    pub fn iter(&self) -> impl Iterator<Item = Arc<dyn LlmProvider>> + '_ {
+        self.providers.values().cloned()
+    }
+
+    /// Disabled providers and why, by name.
+    pub fn disabled(&self) -> impl Iterator<Item = (&'static str, &str)> + '_ {
+        self.disabled
+            .iter()
+            .map(|(name, reason)| (*name, reason.as_str()))
+    }
+
+    /// Registered and disabled providers, by name.
+    pub fn infos(&self) -> Vec<ProviderInfo> {
+        let enabled = self.providers.values().map(|provider| ProviderInfo {
+            name: provider.name(),
+            enabled: true,
+            default_model: Some(provider.default_model().to_string()),
+            reason: None,
+        });
+        let disabled = self.disabled().map(|(name, reason)| ProviderInfo {
+            name,
+            enabled: false,
+            default_model: None,
+            reason: Some(reason.to_string()),
+        });
+        let mut infos: Vec<ProviderInfo> = enabled.chain(disabled).collect();
+        infos.sort_by_key(|info| info.name);
+        infos
+    }
+}
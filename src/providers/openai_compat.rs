@@ -0,0 +1,467 @@
+//! Request building and response parsing shared by every provider that
+//! exposes an OpenAI-compatible `/chat/completions` endpoint.
+
+use super::retry::RetryPolicy;
+use super::{ChatRequest, ChatResponse, ChatStream, ProviderError, StreamEvent, Usage};
+use crate::metrics::metrics;
+use crate::telemetry::{RequestId, REQUEST_ID_HEADER};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+/// How long to wait for an answer (or, when streaming, for the first byte).
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct OpenAiCompatClient {
+    /// Provider name, used in metrics.
+    name: &'static str,
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    default_model: String,
+    timeout: Duration,
+    retry: RetryPolicy,
+}
+
+impl OpenAiCompatClient {
+    pub fn new(
+        name: &'static str,
+        base_url: impl Into<String>,
+        api_key: String,
+        default_model: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key,
+            default_model: default_model.into(),
+            timeout: DEFAULT_TIMEOUT,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Sends requests to `base_url` instead, e.g. a proxy or a local stub.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    fn request_body(&self, request: &ChatRequest, stream: bool) -> Value {
+        let model = request
+            .options
+            .model
+            .as_deref()
+            .unwrap_or(&self.default_model);
+        let mut body = json!({
+            "model": model,
+            "messages": request.messages,
+        });
+        if let Ok(Value::Object(options)) = serde_json::to_value(&request.options) {
+            body.as_object_mut()
+                .expect("body is an object")
+                .extend(options);
+        }
+        if stream {
+            body["stream"] = json!(true);
+            // Ask for a final chunk carrying token usage.
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+        body
+    }
+
+    fn model<'a>(&'a self, request: &'a ChatRequest) -> &'a str {
+        request
+            .options
+            .model
+            .as_deref()
+            .unwrap_or(&self.default_model)
+    }
+
+    /// Posts `request` in an `upstream` span, retrying according to the retry policy.
+    async fn post_chat(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, ProviderError> {
+        let span = tracing::info_span!(
+            "upstream",
+            provider = self.name,
+            model = self.model(request),
+            request_id = RequestId::current().as_ref().map(RequestId::as_str),
+            stream,
+        );
+        self.post_with_retries(request, stream)
+            .instrument(span)
+            .await
+    }
+
+    async fn post_with_retries(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, ProviderError> {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let sent = self.send_chat(request, stream).await;
+            self.observe(request, &sent, started);
+            let delay = match sent {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    match self.retry.delay_after_status(
+                        attempt,
+                        response.status(),
+                        response.headers(),
+                    ) {
+                        Some(delay) => {
+                            tracing::warn!(
+                                status = response.status().as_u16(),
+                                retry_in_ms = delay.as_millis() as u64,
+                                "upstream failed, retrying"
+                            );
+                            delay
+                        }
+                        None => return check_status(response).await,
+                    }
+                }
+                Err(ProviderError::Request(e)) => match self.retry.delay_after_error(attempt, &e) {
+                    Some(delay) => {
+                        tracing::warn!(
+                            error = %e,
+                            retry_in_ms = delay.as_millis() as u64,
+                            "upstream unreachable, retrying"
+                        );
+                        delay
+                    }
+                    None => return Err(ProviderError::Request(e)),
+                },
+                Err(e) => return Err(e),
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Logs one attempt and records it in the upstream metrics.
+    fn observe(
+        &self,
+        request: &ChatRequest,
+        sent: &Result<reqwest::Response, ProviderError>,
+        started: Instant,
+    ) {
+        let status = match sent {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(ProviderError::Timeout) => "timeout".to_string(),
+            Err(ProviderError::Request(_)) => "network".to_string(),
+            // `send_chat` fails only with the two errors above; upstream
+            // statuses and bad bodies are turned into errors after this.
+            Err(_) => return,
+        };
+        let elapsed = started.elapsed();
+        tracing::info!(
+            status = %status,
+            latency_ms = elapsed.as_millis() as u64,
+            "upstream responded"
+        );
+        metrics().observe_upstream(self.name, self.model(request), &status, elapsed);
+    }
+
+    async fn send_chat(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, ProviderError> {
+        let mut builder = self
+            .http
+            .post(format!("{}/chat/completions", self.base_url))
+            .bearer_auth(&self.api_key)
+            .json(&self.request_body(request, stream));
+        if let Some(request_id) = RequestId::current() {
+            builder = builder.header(REQUEST_ID_HEADER, request_id.as_str());
+        }
+        let response = if stream {
+            // A whole-request timeout would cut off long answers, so only
+            // wait a bounded time for the response headers.
+            tokio::time::timeout(self.timeout, builder.send())
+                .await
+                .map_err(|_| ProviderError::Timeout)??
+        } else {
+            builder.timeout(self.timeout).send().await?
+        };
+        Ok(response)
+    }
+
+    pub async fn chat(&self, request: ChatRequest) -> Result<ChatResponse, ProviderError> {
+        let response = self.post_chat(&request, false).await?;
+        let completion: Completion = response.json().await.map_err(body_error)?;
+
+        let choice =
+            completion.choices.into_iter().next().ok_or_else(|| {
+                ProviderError::InvalidResponse("no choices in response".to_string())
+            })?;
+
+        Ok(ChatResponse {
+            content: choice.message.content.unwrap_or_default(),
+            tool_calls: choice.message.tool_calls.unwrap_or_default(),
+            model: completion.model,
+            finish_reason: choice.finish_reason,
+            usage: completion.usage,
+        })
+    }
+
+    pub async fn chat_stream(&self, request: ChatRequest) -> Result<ChatStream, ProviderError> {
+        let response = self.post_chat(&request, true).await?;
+
+        let state = StreamState {
+            body: response.bytes_stream().boxed(),
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            finish_reason: None,
+            usage: None,
+            finished: false,
+        };
+
+        Ok(stream::unfold(state, StreamState::next).boxed())
+    }
+
+    pub async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let response = self
+            .http
+            .get(format!("{}/models", self.base_url))
+            .bearer_auth(&self.api_key)
+            .send()
+            .await?;
+        let models: ModelList = check_status(response)
+            .await?
+            .json()
+            .await
+            .map_err(body_error)?;
+        Ok(models.data.into_iter().map(|m| m.id).collect())
+    }
+}
+
+/// An error reading a response body: the client timeout covers the body
+/// too, so it can expire here rather than while sending.
+fn body_error(e: reqwest::Error) -> ProviderError {
+    if e.is_timeout() {
+        ProviderError::Timeout
+    } else {
+        ProviderError::InvalidResponse(e.to_string())
+    }
+}
+
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, ProviderError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response
+        .text()
+        .await
+        .unwrap_or_else(|_| "Unknown error".to_string());
+    Err(ProviderError::Upstream {
+        // reqwest and actix pin different `http` versions, so go through the code.
+        status: actix_web::http::StatusCode::from_u16(status.as_u16())
+            .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY),
+        body,
+    })
+}
+
+#[derive(Deserialize)]
+struct Completion {
+    #[serde(default)]
+    model: String,
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: Option<String>,
+    tool_calls: Option<Vec<Value>>,
+}
+
+#[derive(Deserialize)]
+struct Chunk {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    usage: Option<Usage>,
+    /// Groq reports usage here instead of the top-level `usage` field.
+    x_groq: Option<GroqExtra>,
+    /// Set when the upstream fails after the 200 headers were already sent.
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct GroqExtra {
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    #[serde(default)]
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct Delta {
+    content: Option<String>,
+    tool_calls: Option<Vec<Value>>,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+/// Turns the upstream `text/event-stream` body into `StreamEvent`s.
+struct StreamState {
+    body: futures_util::stream::BoxStream<'static, reqwest::Result<bytes::Bytes>>,
+    /// Raw bytes not yet terminated by a newline; kept as bytes so multi-byte
+    /// characters split across network chunks decode correctly.
+    buffer: Vec<u8>,
+    pending: VecDeque<Result<StreamEvent, ProviderError>>,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+    finished: bool,
+}
+
+impl StreamState {
+    async fn next(mut self) -> Option<(Result<StreamEvent, ProviderError>, Self)> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some((event, self));
+            }
+            if self.finished {
+                return None;
+            }
+            match self.body.next().await {
+                Some(Ok(bytes)) => {
+                    self.buffer.extend_from_slice(&bytes);
+                    self.drain_lines();
+                }
+                Some(Err(e)) => {
+                    self.finished = true;
+                    self.pending.push_back(Err(e.into()));
+                }
+                None => {
+                    // The last line may lack its newline.
+                    if !self.buffer.is_empty() {
+                        self.buffer.push(b'\n');
+                        self.drain_lines();
+                    }
+                    if self.finish_reason.is_some() {
+                        // Finished, only the `[DONE]` after it is missing.
+                        self.finish();
+                    } else if !self.finished {
+                        self.finished = true;
+                        self.pending.push_back(Err(ProviderError::InvalidResponse(
+                            "stream ended before the answer was finished".to_string(),
+                        )));
+                    }
+                }
+            }
+        }
+    }
+
+    fn drain_lines(&mut self) {
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&raw);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                // Comments (`: keep-alive`), `event:` lines and blank separators.
+                continue;
+            };
+            let data = data.trim_start();
+            if data == "[DONE]" {
+                self.finish();
+                return;
+            }
+            match serde_json::from_str::<Chunk>(data) {
+                Ok(chunk) => {
+                    self.push_chunk(chunk);
+                    if self.finished {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    self.finished = true;
+                    self.pending
+                        .push_back(Err(ProviderError::InvalidResponse(e.to_string())));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: Chunk) {
+        if let Some(error) = chunk.error {
+            self.finished = true;
+            let message = error
+                .get("message")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .unwrap_or_else(|| error.to_string());
+            self.pending.push_back(Err(ProviderError::Upstream {
+                status: actix_web::http::StatusCode::BAD_GATEWAY,
+                body: message,
+            }));
+            return;
+        }
+        if let Some(usage) = chunk.usage.or(chunk.x_groq.and_then(|x| x.usage)) {
+            self.usage = Some(usage);
+        }
+        for choice in chunk.choices {
+            if let Some(content) = choice.delta.content.filter(|c| !c.is_empty()) {
+                self.pending.push_back(Ok(StreamEvent::Delta(content)));
+            }
+            if let Some(calls) = choice.delta.tool_calls.filter(|c| !c.is_empty()) {
+                self.pending.push_back(Ok(StreamEvent::ToolCalls(calls)));
+            }
+            if choice.finish_reason.is_some() {
+                self.finish_reason = choice.finish_reason;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.pending.push_back(Ok(StreamEvent::Done {
+            finish_reason: self.finish_reason.take(),
+            usage: self.usage.take(),
+        }));
+    }
+}
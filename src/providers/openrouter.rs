@@ -0,0 +1,62 @@
+use super::openai_compat::OpenAiCompatClient;
+use super::{ChatRequest, ChatResponse, ChatStream, LlmProvider, ProviderError, RetryPolicy};
+use async_trait::async_trait;
+use std::time::Duration;
+
+pub struct OpenRouterProvider {
+    client: OpenAiCompatClient,
+}
+
+impl OpenRouterProvider {
+    pub const DEFAULT_MODEL: &'static str = "meta-llama/llama-3.2-3b-instruct";
+    pub const DEFAULT_BASE_URL: &'static str = "https://openrouter.ai/api/v1";
+
+    pub fn new(api_key: String, default_model: impl Into<String>) -> Self {
+        Self {
+            client: OpenAiCompatClient::new(
+                "openrouter",
+                Self::DEFAULT_BASE_URL,
+                api_key,
+                default_model,
+            ),
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.client = self.client.with_base_url(base_url);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = self.client.with_timeout(timeout);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.client = self.client.with_retry(retry);
+        self
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenRouterProvider {
+    fn name(&self) -> &'static str {
+        "openrouter"
+    }
+
+    fn default_model(&self) -> &str {
+        self.client.default_model()
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<ChatResponse, ProviderError> {
+        self.client.chat(request).await
+    }
+
+    async fn chat_stream(&self, request: ChatRequest) -> Result<ChatStream, ProviderError> {
+        self.client.chat_stream(request).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        self.client.list_models().await
+    }
+}
@@ -0,0 +1,246 @@
+//! Retry policy for upstream chat calls.
+//!
+//! A chat call is retried when the connection could not be made or the
+//! upstream answered 429 or 5xx. Nothing has been relayed to the client at
+//! that point (streams are only retried before they open), so a retry is
+//! safe. The wait is the upstream's own hint when it sends one (`Retry-After`
+//! or a rate-limit reset header), otherwise exponential backoff with full
+//! jitter. Hints longer than `max_delay` are not waited for: the error is
+//! returned so a fallback model can take over instead.
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; `0` disables retrying.
+    pub max_retries: u32,
+    /// Backoff before the first retry; doubles with every further one.
+    pub base_delay: Duration,
+    /// Upper bound for any single wait.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retrying a request that got `status`, if at all.
+    pub(crate) fn delay_after_status(
+        &self,
+        attempt: u32,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+        if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+            return None;
+        }
+        match upstream_delay(headers) {
+            Some(delay) if delay > self.max_delay => None,
+            Some(delay) => Some(delay),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+
+    /// How long to wait before retrying a request that failed with `error`, if at all.
+    pub(crate) fn delay_after_error(
+        &self,
+        attempt: u32,
+        error: &reqwest::Error,
+    ) -> Option<Duration> {
+        (attempt < self.max_retries && error.is_connect()).then(|| self.backoff(attempt))
+    }
+
+    /// A random wait between zero and `base_delay * 2^attempt`, capped at `max_delay`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        ceiling.mul_f64(fastrand::f64())
+    }
+}
+
+/// The wait the upstream asks for, from `Retry-After` or rate-limit reset headers.
+fn upstream_delay(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(value) = headers.get(RETRY_AFTER).and_then(|v| v.to_str().ok()) {
+        // Either delta-seconds or an HTTP date.
+        if let Ok(seconds) = value.trim().parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        if let Ok(at) = httpdate::parse_http_date(value) {
+            return Some(at.duration_since(SystemTime::now()).unwrap_or_default());
+        }
+    }
+
+    // Groq and OpenAI: "2m59.56s", "7.66s", "120ms".
+    let resets = ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"];
+    if let Some(delay) = resets
+        .iter()
+        .filter_map(|name| header(name).and_then(parse_duration))
+        .max()
+    {
+        return Some(delay);
+    }
+
+    // OpenRouter sends a Unix timestamp in milliseconds; the IETF draft
+    // `RateLimit-Reset` is a number of seconds.
+    if let Some(reset) = header("x-ratelimit-reset").and_then(|v| v.trim().parse::<u64>().ok()) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let at = if reset > 1_000_000_000_000 {
+            Duration::from_millis(reset)
+        } else {
+            Duration::from_secs(reset)
+        };
+        return Some(at.saturating_sub(now));
+    }
+    header("ratelimit-reset")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Parses Go-style durations such as `1m30.5s`, `250ms` or `6m0s`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        total += number * seconds;
+        rest = &rest[unit_len..];
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn unix_now() -> Duration {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+    }
+
+    #[test]
+    fn retry_after_is_seconds_or_an_http_date() {
+        let delay = upstream_delay(&headers(&[("retry-after", "7")]));
+        assert_eq!(delay, Some(Duration::from_secs(7)));
+
+        let at = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        let delay = upstream_delay(&headers(&[("retry-after", &at)])).unwrap();
+        assert!(
+            delay > Duration::from_secs(28) && delay <= Duration::from_secs(30),
+            "{:?}",
+            delay
+        );
+
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(30));
+        let delay = upstream_delay(&headers(&[("retry-after", &past)]));
+        assert_eq!(delay, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn reset_headers_take_the_longest_go_duration() {
+        let delay = upstream_delay(&headers(&[
+            ("x-ratelimit-reset-requests", "2m59.5s"),
+            ("x-ratelimit-reset-tokens", "120ms"),
+        ]));
+        assert_eq!(delay, Some(Duration::from_secs_f64(179.5)));
+        assert_eq!(parse_duration("1h0m1s"), Some(Duration::from_secs(3601)));
+        assert_eq!(parse_duration("5 minutes"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn x_ratelimit_reset_is_a_unix_timestamp() {
+        let in_a_minute = (unix_now() + Duration::from_secs(60))
+            .as_millis()
+            .to_string();
+        let delay = upstream_delay(&headers(&[("x-ratelimit-reset", &in_a_minute)])).unwrap();
+        assert!(
+            delay > Duration::from_secs(58) && delay <= Duration::from_secs(60),
+            "{:?}",
+            delay
+        );
+
+        let in_seconds = (unix_now() + Duration::from_secs(60)).as_secs().to_string();
+        let delay = upstream_delay(&headers(&[("x-ratelimit-reset", &in_seconds)])).unwrap();
+        assert!(
+            delay > Duration::from_secs(58) && delay <= Duration::from_secs(60),
+            "{:?}",
+            delay
+        );
+
+        let delay = upstream_delay(&headers(&[("ratelimit-reset", "12")]));
+        assert_eq!(delay, Some(Duration::from_secs(12)));
+        assert_eq!(upstream_delay(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn only_429_and_5xx_are_retried_and_long_hints_are_not_waited_for() {
+        let policy = RetryPolicy::default();
+        let none = HeaderMap::new();
+        for status in [StatusCode::TOO_MANY_REQUESTS, StatusCode::BAD_GATEWAY] {
+            let delay = policy.delay_after_status(0, status, &none).unwrap();
+            assert!(delay <= policy.base_delay, "{:?}", delay);
+        }
+        assert_eq!(
+            policy.delay_after_status(0, StatusCode::BAD_REQUEST, &none),
+            None
+        );
+        assert_eq!(
+            policy.delay_after_status(policy.max_retries, StatusCode::BAD_GATEWAY, &none),
+            None
+        );
+
+        let hint = headers(&[("retry-after", "3")]);
+        let delay = policy.delay_after_status(0, StatusCode::TOO_MANY_REQUESTS, &hint);
+        assert_eq!(delay, Some(Duration::from_secs(3)));
+        let hint = headers(&[("retry-after", "60")]);
+        assert_eq!(
+            policy.delay_after_status(0, StatusCode::TOO_MANY_REQUESTS, &hint),
+            None
+        );
+    }
+}
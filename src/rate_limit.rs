@@ -0,0 +1,414 @@
+//! Per-client rate limiting for the endpoints that spend provider credits.
+//!
+//! Every limited route has a token bucket per client: it holds up to
+//! `requests` tokens, refills at `requests / period` and each request takes
+//! one. Clients are told where they stand through the IETF draft
+//! `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` and
+//! `RateLimit-Policy` headers; a client with an empty bucket gets a `429` with
+//! `Retry-After`. Buckets that have refilled completely carry no information
+//! and are dropped by `RateLimits::evict_idle`.
+//!
+//! Wrap a route in `ApiKeys::require` outside this middleware so that
+//! authenticated clients are counted per key rather than per IP. Clients
+//! without a key are counted by the address they connect from, or, behind a
+//! trusted proxy, by the address that proxy reports in `X-Forwarded-For`.
+
+use crate::auth::Principal;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::{http::StatusCode, Error, HttpMessage, HttpResponse};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Routes that can be limited. Limits on `/completion` and `/groqlive` also
+/// cover their `/stream` variants.
+pub const LIMITED_ROUTES: [&str; 5] = [
+    "/completion",
+    "/groqlive",
+    "/summarize",
+    "/conversations/{id}/messages",
+    "/v1/chat/completions",
+];
+
+/// `requests` per `period`, written as `20/60` (twenty requests per minute).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit '{}', expected requests/seconds", s);
+        let (requests, seconds) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            requests,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+impl Quota {
+    /// Time for one token to refill.
+    fn refill_interval(&self) -> Duration {
+        self.period / self.requests
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Where a client stands after a request was counted (or refused).
+#[derive(Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub remaining: u32,
+    /// Until the bucket is full again.
+    pub reset: Duration,
+    /// Until the next request would be allowed; zero when `allowed`.
+    pub retry_after: Duration,
+}
+
+/// Token buckets for one route, keyed by client.
+pub struct RateLimiter {
+    quota: Quota,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Takes a token from `client`'s bucket if there is one.
+    pub fn check(&self, client: &str) -> Decision {
+        let capacity = f64::from(self.quota.requests);
+        let per_token = self.quota.refill_interval().as_secs_f64();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() / per_token;
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let retry_after = if allowed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - bucket.tokens) * per_token)
+        };
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            reset: Duration::from_secs_f64((capacity - bucket.tokens) * per_token),
+            retry_after,
+        }
+    }
+
+    /// Drops buckets that have refilled completely since their last request.
+    pub fn evict_idle(&self) {
+        let now = Instant::now();
+        let capacity = f64::from(self.quota.requests);
+        let per_token = self.quota.refill_interval().as_secs_f64();
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let refilled = now.duration_since(bucket.updated).as_secs_f64() / per_token;
+            bucket.tokens + refilled < capacity
+        });
+    }
+}
+
+/// Proxies whose `X-Forwarded-For` header is believed, as addresses or CIDR
+/// ranges.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    /// Parses entries such as `10.0.0.0/8`, `127.0.0.1` or `fd00::/8`.
+    pub fn parse<S: AsRef<str>>(entries: &[S]) -> Result<Self, String> {
+        let ranges = entries
+            .iter()
+            .map(|entry| {
+                let entry = entry.as_ref().trim();
+                let invalid = || {
+                    format!(
+                        "invalid proxy '{}', expected an IP address or CIDR range",
+                        entry
+                    )
+                };
+                let (address, prefix) = match entry.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (entry, None),
+                };
+                let address = canonical(address.parse().map_err(|_| invalid())?);
+                let bits = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse()
+                        .ok()
+                        .filter(|&prefix| prefix <= bits)
+                        .ok_or_else(invalid)?,
+                    None => bits,
+                };
+                Ok((address, prefix))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { ranges })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = canonical(address);
+        self.ranges
+            .iter()
+            .any(|&(network, prefix)| match (address, network) {
+                (IpAddr::V4(a), IpAddr::V4(n)) => {
+                    same_prefix(u32::from(a).into(), u32::from(n).into(), 32, prefix)
+                }
+                (IpAddr::V6(a), IpAddr::V6(n)) => {
+                    same_prefix(u128::from(a), u128::from(n), 128, prefix)
+                }
+                _ => false,
+            })
+    }
+
+    /// The address a request is counted against: the peer, unless the peer is
+    /// a trusted proxy. Then it is the right-most `X-Forwarded-For` hop that
+    /// is not a trusted proxy itself, as everything left of that hop was
+    /// written by the client.
+    fn client_address(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let mut client = canonical(req.peer_addr()?.ip());
+        if !self.contains(client) {
+            return Some(client);
+        }
+        let hops: Vec<&str> = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in hops.into_iter().rev() {
+            // Garbage can only come from the client; stop at the last proxy.
+            let Ok(address) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = canonical(address);
+            if !self.contains(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+/// IPv4 addresses mapped into IPv6 (`::ffff:10.0.0.1`) as plain IPv4.
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+fn same_prefix(a: u128, b: u128, bits: u32, prefix: u8) -> bool {
+    let shift = bits - u32::from(prefix);
+    a.checked_shr(shift).unwrap_or(0) == b.checked_shr(shift).unwrap_or(0)
+}
+
+/// The configured limit of every limited route.
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    limiters: BTreeMap<String, Arc<RateLimiter>>,
+    proxies: Arc<TrustedProxies>,
+}
+
+impl RateLimits {
+    /// Builds the limits from `(route, quota)` pairs such as `("/completion", "20/60")`.
+    pub fn new<'a>(limits: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self, String> {
+        let mut limiters = BTreeMap::new();
+        for (route, quota) in limits {
+            let route = route.trim();
+            if !LIMITED_ROUTES.contains(&route) {
+                return Err(format!(
+                    "cannot rate limit '{}' (supported: {})",
+                    route,
+                    LIMITED_ROUTES.join(", ")
+                ));
+            }
+            limiters.insert(
+                route.to_string(),
+                Arc::new(RateLimiter::new(quota.parse()?)),
+            );
+        }
+        Ok(Self {
+            limiters,
+            proxies: Arc::default(),
+        })
+    }
+
+    /// Believes `X-Forwarded-For` on connections from `proxies`.
+    pub fn with_trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.proxies = Arc::new(proxies);
+        self
+    }
+
+    /// Routes with a limit, and their quotas.
+    pub fn quotas(&self) -> impl Iterator<Item = (&str, Quota)> {
+        self.limiters
+            .iter()
+            .map(|(route, limiter)| (route.as_str(), limiter.quota()))
+    }
+
+    /// Middleware enforcing `route`'s limit; passes everything through if it has none.
+    pub fn layer(&self, route: &str) -> RateLimit {
+        RateLimit {
+            limiter: self.limiters.get(route).cloned(),
+            proxies: self.proxies.clone(),
+        }
+    }
+
+    pub fn evict_idle(&self) {
+        for limiter in self.limiters.values() {
+            limiter.evict_idle();
+        }
+    }
+}
+
+/// Identifies the client a request is counted against.
+///
+/// Clients authenticated with an API key are counted per key, everyone else
+/// per IP address.
+fn client_key(req: &ServiceRequest, proxies: &TrustedProxies) -> String {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        if principal.is_authenticated() {
+            return format!("key:{}", principal.label);
+        }
+    }
+    match proxies.client_address(req) {
+        Some(address) => format!("ip:{}", address),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+/// Rounds up to whole seconds, the unit of every rate-limit header.
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn insert_headers(headers: &mut HeaderMap, quota: Quota, decision: &Decision) {
+    let values = [
+        ("ratelimit-limit", quota.requests.to_string()),
+        ("ratelimit-remaining", decision.remaining.to_string()),
+        ("ratelimit-reset", ceil_secs(decision.reset).to_string()),
+        (
+            "ratelimit-policy",
+            format!("{};w={}", quota.requests, quota.period.as_secs()),
+        ),
+    ];
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+/// Middleware for one route, created by `RateLimits::layer`.
+#[derive(Clone)]
+pub struct RateLimit {
+    limiter: Option<Arc<RateLimiter>>,
+    proxies: Arc<TrustedProxies>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+            proxies: self.proxies.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Option<Arc<RateLimiter>>,
+    proxies: Arc<TrustedProxies>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let Some(limiter) = self.limiter.clone() else {
+            return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+        };
+
+        let quota = limiter.quota();
+        let decision = limiter.check(&client_key(&req, &self.proxies));
+        if !decision.allowed {
+            let retry_after = ceil_secs(decision.retry_after);
+            let mut response = HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+                .insert_header((RETRY_AFTER, retry_after))
+                .json(json!({
+                    "error": format!("Rate limit exceeded, retry in {} s", retry_after),
+                }));
+            insert_headers(response.headers_mut(), quota, &decision);
+            return Box::pin(async move { Ok(req.into_response(response).map_into_right_body()) });
+        }
+
+        Box::pin(async move {
+            let mut response = service.call(req).await?;
+            insert_headers(response.headers_mut(), quota, &decision);
+            Ok(response.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,98 @@
+//! Relays a provider `ChatStream` to HTTP clients as Server-Sent Events.
+//!
+//! The wire format is deliberately small:
+//! - `event: delta` with `{"content": "..."}` for each chunk of text,
+//! - `event: done` with `{"finish_reason": ..., "usage": ...}` once at the end,
+//!   plus whatever fields the caller's `on_done` returns for the final usage,
+//! - `event: error` with `{"error": "..."}` if the upstream fails mid-stream.
+//!
+//! If the client disconnects, actix drops the response body, which drops the
+//! provider stream and with it the upstream HTTP connection, so we stop
+//! paying for tokens nobody will read.
+
+use crate::providers::{ChatStream, StreamEvent, Usage};
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde_json::{json, Map, Value};
+
+/// Encodes a single named SSE event.
+pub fn event(name: &str, data: &Value) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+/// Wraps `stream` from `provider` in a `text/event-stream` response.
+///
+/// `on_done` is called once with the usage the upstream reported; the fields
+/// it returns are merged into the `done` event, replacing defaults such as
+/// `usage`.
+pub fn stream_response<F>(provider: &'static str, stream: ChatStream, on_done: F) -> HttpResponse
+where
+    F: FnOnce(Option<Usage>) -> Map<String, Value> + 'static,
+{
+    let mut on_done = Some(on_done);
+    let mut guard = DisconnectGuard {
+        span: tracing::Span::current(),
+        provider,
+        finished: false,
+    };
+    let body = stream.map(move |item| {
+        if matches!(item, Ok(StreamEvent::Done { .. }) | Err(_)) {
+            guard.finish();
+        }
+        let bytes = match item {
+            Ok(StreamEvent::Delta(content)) => event("delta", &json!({ "content": content })),
+            Ok(StreamEvent::ToolCalls(calls)) => {
+                event("tool_calls", &json!({ "tool_calls": calls }))
+            }
+            Ok(StreamEvent::Done {
+                finish_reason,
+                usage,
+            }) => {
+                let mut data = Map::new();
+                data.insert("finish_reason".to_string(), json!(finish_reason));
+                data.insert("usage".to_string(), json!(usage));
+                if let Some(on_done) = on_done.take() {
+                    data.extend(on_done(usage));
+                }
+                event("done", &Value::Object(data))
+            }
+            Err(e) => event("error", &json!({ "error": e.to_string() })),
+        };
+        Ok::<_, actix_web::Error>(bytes)
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // Stop reverse proxies (Render, nginx) from buffering the stream.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body)
+}
+
+/// Notes streams that were dropped before the upstream finished.
+struct DisconnectGuard {
+    /// The request's span, which has ended by the time the stream drops.
+    span: tracing::Span,
+    provider: &'static str,
+    finished: bool,
+}
+
+impl DisconnectGuard {
+    fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.span.in_scope(|| {
+                tracing::info!(
+                    provider = self.provider,
+                    "client disconnected, cancelled upstream stream"
+                )
+            });
+        }
+    }
+}
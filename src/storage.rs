@@ -0,0 +1,394 @@
+//! SQLite persistence for conversations, so history survives restarts, and
+//! for the daily token usage totals per API key.
+//!
+//! The schema is versioned through `PRAGMA user_version`; `Storage::open`
+//! applies every migration newer than the file's version before returning.
+//! All methods are blocking, so handlers call them through `web::block`.
+
+use crate::conversations::{Conversation, Turn};
+use crate::providers::Usage;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Migrations in order; index + 1 is the schema version they bring the file to.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE conversations (
+        id          TEXT PRIMARY KEY,
+        provider    TEXT NOT NULL,
+        created_at  TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        updated_at  TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE messages (
+        id                INTEGER PRIMARY KEY AUTOINCREMENT,
+        conversation_id   TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+        role              TEXT NOT NULL,
+        content           TEXT NOT NULL,
+        provider          TEXT,
+        model             TEXT,
+        latency_ms        INTEGER,
+        prompt_tokens     INTEGER,
+        completion_tokens INTEGER,
+        total_tokens      INTEGER,
+        created_at        TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE INDEX messages_conversation_id ON messages(conversation_id, id);",
+    "CREATE TABLE usage_daily (
+        day               TEXT NOT NULL,
+        api_key           TEXT NOT NULL,
+        provider          TEXT NOT NULL,
+        model             TEXT NOT NULL,
+        requests          INTEGER NOT NULL DEFAULT 0,
+        prompt_tokens     INTEGER NOT NULL DEFAULT 0,
+        completion_tokens INTEGER NOT NULL DEFAULT 0,
+        total_tokens      INTEGER NOT NULL DEFAULT 0,
+        cost_usd          REAL NOT NULL DEFAULT 0,
+        unpriced_requests INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (day, api_key, provider, model)
+    );",
+    // Conversations from before API keys owned them belonged to everyone.
+    "ALTER TABLE conversations ADD COLUMN owner TEXT NOT NULL DEFAULT 'anonymous';
+    CREATE INDEX conversations_owner ON conversations(owner, updated_at);",
+];
+
+/// How an assistant answer was produced, stored next to the message.
+#[derive(Clone, Debug)]
+pub struct AnswerMeta {
+    pub provider: String,
+    pub model: String,
+    pub latency_ms: u64,
+    pub usage: Option<Usage>,
+}
+
+/// Token and cost totals of one API key on one UTC day.
+#[derive(Debug, Serialize)]
+pub struct UsageTotal {
+    pub day: String,
+    pub api_key: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cost_usd: f64,
+    /// Requests without a known price or usage, not included in `cost_usd`.
+    pub unpriced_requests: u64,
+}
+
+/// Which usage totals to return; `None` fields do not filter.
+#[derive(Debug, Default)]
+pub struct UsageFilter {
+    /// First day included, as `YYYY-MM-DD`.
+    pub from: Option<String>,
+    /// Last day included, as `YYYY-MM-DD`.
+    pub to: Option<String>,
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub provider: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub message_count: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoredMessage {
+    pub role: String,
+    pub content: String,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub latency_ms: Option<u64>,
+    pub usage: Option<Usage>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationRecord {
+    pub id: String,
+    pub owner: String,
+    pub provider: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub messages: Vec<StoredMessage>,
+}
+
+impl ConversationRecord {
+    /// Rebuilds the in-memory session, pairing each user message with the answer after it.
+    pub fn to_conversation(&self) -> Conversation {
+        let mut turns = Vec::new();
+        let mut question: Option<&str> = None;
+        for message in &self.messages {
+            match (message.role.as_str(), question) {
+                ("user", _) => question = Some(&message.content),
+                ("assistant", Some(q)) => {
+                    turns.push(Turn {
+                        question: q.to_string(),
+                        answer: message.content.clone(),
+                    });
+                    question = None;
+                }
+                _ => {}
+            }
+        }
+        Conversation::new(
+            self.id.clone(),
+            self.owner.clone(),
+            self.provider.clone(),
+            turns,
+        )
+    }
+}
+
+pub struct Storage {
+    conn: Mutex<Connection>,
+}
+
+impl Storage {
+    /// Opens (or creates) the database at `path` and runs pending migrations.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Reads from the database, for readiness checks.
+    pub fn ping(&self) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM conversations LIMIT 1",
+            [],
+            |_| Ok(()),
+        )
+    }
+
+    pub fn create_conversation(
+        &self,
+        id: &str,
+        owner: &str,
+        provider: &str,
+    ) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO conversations (id, owner, provider) VALUES (?1, ?2, ?3)",
+            params![id, owner, provider],
+        )?;
+        Ok(())
+    }
+
+    /// Stores one question and its answer atomically; `false` if the
+    /// conversation does not exist (any more).
+    pub fn append_turn(
+        &self,
+        conversation_id: &str,
+        turn: &Turn,
+        meta: &AnswerMeta,
+    ) -> rusqlite::Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let touched = tx.execute(
+            "UPDATE conversations SET updated_at = CURRENT_TIMESTAMP WHERE id = ?1",
+            params![conversation_id],
+        )?;
+        if touched == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT INTO messages (conversation_id, role, content) VALUES (?1, 'user', ?2)",
+            params![conversation_id, turn.question],
+        )?;
+        tx.execute(
+            "INSERT INTO messages (conversation_id, role, content, provider, model, latency_ms,
+                                   prompt_tokens, completion_tokens, total_tokens)
+             VALUES (?1, 'assistant', ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                conversation_id,
+                turn.answer,
+                meta.provider,
+                meta.model,
+                meta.latency_ms as i64,
+                meta.usage.map(|u| u.prompt_tokens),
+                meta.usage.map(|u| u.completion_tokens),
+                meta.usage.map(|u| u.total_tokens),
+            ],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// `owner`'s conversations, most recently active first.
+    pub fn list_conversations(
+        &self,
+        owner: &str,
+        limit: u32,
+        offset: u32,
+    ) -> rusqlite::Result<Vec<ConversationSummary>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.provider, c.created_at, c.updated_at,
+                    (SELECT COUNT(*) FROM messages m WHERE m.conversation_id = c.id)
+             FROM conversations c
+             WHERE c.owner = ?1
+             ORDER BY c.updated_at DESC, c.rowid DESC
+             LIMIT ?2 OFFSET ?3",
+        )?;
+        let rows = stmt.query_map(params![owner, limit, offset], |row| {
+            Ok(ConversationSummary {
+                id: row.get(0)?,
+                provider: row.get(1)?,
+                created_at: row.get(2)?,
+                updated_at: row.get(3)?,
+                message_count: row.get(4)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// The conversation `id`, or `None` if it does not exist or `owner` did not start it.
+    pub fn get_conversation(
+        &self,
+        owner: &str,
+        id: &str,
+    ) -> rusqlite::Result<Option<ConversationRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let Some((provider, created_at, updated_at)) = conn
+            .query_row(
+                "SELECT provider, created_at, updated_at FROM conversations
+                 WHERE id = ?1 AND owner = ?2",
+                params![id, owner],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT role, content, provider, model, latency_ms,
+                    prompt_tokens, completion_tokens, total_tokens, created_at
+             FROM messages WHERE conversation_id = ?1 ORDER BY id",
+        )?;
+        let messages = stmt
+            .query_map(params![id], |row| {
+                let usage = match (row.get(5)?, row.get(6)?, row.get(7)?) {
+                    (Some(prompt_tokens), Some(completion_tokens), Some(total_tokens)) => {
+                        Some(Usage {
+                            prompt_tokens,
+                            completion_tokens,
+                            total_tokens,
+                        })
+                    }
+                    _ => None,
+                };
+                Ok(StoredMessage {
+                    role: row.get(0)?,
+                    content: row.get(1)?,
+                    provider: row.get(2)?,
+                    model: row.get(3)?,
+                    latency_ms: row.get::<_, Option<i64>>(4)?.map(|ms| ms as u64),
+                    usage,
+                    created_at: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(Some(ConversationRecord {
+            id: id.to_string(),
+            owner: owner.to_string(),
+            provider,
+            created_at,
+            updated_at,
+            messages,
+        }))
+    }
+
+    /// Adds one answered request to today's totals for `api_key` and `model`.
+    ///
+    /// `cost_usd` is `None` when the usage or the model's price is unknown.
+    pub fn record_usage(
+        &self,
+        api_key: &str,
+        provider: &str,
+        model: &str,
+        usage: Option<Usage>,
+        cost_usd: Option<f64>,
+    ) -> rusqlite::Result<()> {
+        let usage = usage.unwrap_or_default();
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO usage_daily (day, api_key, provider, model, requests, prompt_tokens,
+                                      completion_tokens, total_tokens, cost_usd, unpriced_requests)
+             VALUES (date('now'), ?1, ?2, ?3, 1, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (day, api_key, provider, model) DO UPDATE SET
+                requests = requests + 1,
+                prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                completion_tokens = completion_tokens + excluded.completion_tokens,
+                total_tokens = total_tokens + excluded.total_tokens,
+                cost_usd = cost_usd + excluded.cost_usd,
+                unpriced_requests = unpriced_requests + excluded.unpriced_requests",
+            params![
+                api_key,
+                provider,
+                model,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens,
+                cost_usd.unwrap_or(0.0),
+                cost_usd.is_none() as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Totals per day and API key, most recent day first.
+    pub fn usage_totals(&self, filter: &UsageFilter) -> rusqlite::Result<Vec<UsageTotal>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT day, api_key, SUM(requests), SUM(prompt_tokens), SUM(completion_tokens),
+                    SUM(total_tokens), SUM(cost_usd), SUM(unpriced_requests)
+             FROM usage_daily
+             WHERE (?1 IS NULL OR day >= ?1) AND (?2 IS NULL OR day <= ?2)
+               AND (?3 IS NULL OR api_key = ?3)
+             GROUP BY day, api_key
+             ORDER BY day DESC, api_key",
+        )?;
+        let rows = stmt.query_map(params![filter.from, filter.to, filter.api_key], |row| {
+            Ok(UsageTotal {
+                day: row.get(0)?,
+                api_key: row.get(1)?,
+                requests: row.get::<_, i64>(2)? as u64,
+                prompt_tokens: row.get::<_, i64>(3)? as u64,
+                completion_tokens: row.get::<_, i64>(4)? as u64,
+                total_tokens: row.get::<_, i64>(5)? as u64,
+                cost_usd: row.get(6)?,
+                unpriced_requests: row.get::<_, i64>(7)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Deletes the conversation and its messages; returns `false` if it did
+    /// not exist or `owner` did not start it.
+    pub fn delete_conversation(&self, owner: &str, id: &str) -> rusqlite::Result<bool> {
+        let deleted = self.conn.lock().unwrap().execute(
+            "DELETE FROM conversations WHERE id = ?1 AND owner = ?2",
+            params![id, owner],
+        )?;
+        Ok(deleted > 0)
+    }
+}
+
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!(
+            "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+            sql,
+            index + 1
+        ))?;
+    }
+    Ok(())
+}
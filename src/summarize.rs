@@ -0,0 +1,428 @@
+//! Map-reduce summarization for documents longer than the model context.
+//!
+//! `ProviderExecutor` adapts an `LlmProvider` to llm-chain's `Executor` trait,
+//! counting tokens with tiktoken's `cl100k_base` encoding. That is exact for
+//! OpenAI models and a close enough estimate for the others to keep every
+//! prompt under the configured budget. llm-chain's map-reduce chain then
+//! splits the text into chunks that fit, summarizes them concurrently and
+//! folds the partial summaries together until one is left.
+
+use crate::providers::{ChatOptions, ChatRequest, ChatResponse, LlmProvider, ProviderError, Usage};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use async_trait::async_trait;
+use llm_chain::chains::map_reduce::{Chain, MapReduceChainError};
+use llm_chain::frame::FormatAndExecuteError;
+use llm_chain::options::{ModelRef, Opt, OptDiscriminants, Options};
+use llm_chain::output::Output;
+use llm_chain::prompt::{ChatMessageCollection, Data, Prompt, StringTemplate};
+use llm_chain::step::Step;
+use llm_chain::tokens::{
+    ExecutorTokenCountExt, PromptTokensError, TokenCollection, TokenCount, Tokenizer,
+    TokenizerError,
+};
+use llm_chain::traits::{Executor, ExecutorCreationError, ExecutorError};
+use llm_chain::Parameters;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tiktoken_rs::CoreBPE;
+
+/// Smallest prompt budget that leaves room for text next to the instructions.
+pub const MIN_PROMPT_TOKENS: usize = 256;
+/// Upper bound on chunks per request, since every chunk is one upstream call.
+pub const MAX_CHUNKS: usize = 32;
+
+const MAP_SYSTEM: &str = "You summarize documents. Keep names, numbers and conclusions.";
+const MAP_USER: &str = "Write a concise summary of this part of a longer document:\n\n{{text}}";
+const REDUCE_SYSTEM: &str = "You combine partial summaries of one document.";
+const REDUCE_USER: &str =
+    "Merge these summaries of consecutive parts of a document into a single coherent summary:\n\n{{text}}";
+
+#[derive(Debug)]
+pub enum SummarizeError {
+    /// The server was started without a summarizer.
+    NotConfigured,
+    EmptyInput,
+    TooLong {
+        chunks: usize,
+    },
+    /// A request option llm-chain's `Options` cannot carry.
+    Unsupported(&'static str),
+    Provider(ProviderError),
+    Chain(String),
+}
+
+impl fmt::Display for SummarizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SummarizeError::NotConfigured => write!(f, "summarizer not configured"),
+            SummarizeError::EmptyInput => write!(f, "Text to summarize is empty"),
+            SummarizeError::TooLong { chunks } => write!(
+                f,
+                "Text is too long: it needs {} chunks, at most {} are allowed",
+                chunks, MAX_CHUNKS
+            ),
+            SummarizeError::Unsupported(option) => {
+                write!(f, "{} is not supported for summaries", option)
+            }
+            SummarizeError::Provider(e) => e.fmt(f),
+            SummarizeError::Chain(e) => write!(f, "Summarization failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SummarizeError {}
+
+impl From<MapReduceChainError> for SummarizeError {
+    fn from(e: MapReduceChainError) -> Self {
+        // Dig the provider error back out so upstream statuses pass through.
+        match e {
+            MapReduceChainError::FormatAndExecuteError(FormatAndExecuteError::Execute(
+                ExecutorError::InnerError(inner),
+            )) => match inner.downcast::<ProviderError>() {
+                Ok(provider_error) => SummarizeError::Provider(*provider_error),
+                Err(other) => SummarizeError::Chain(other.to_string()),
+            },
+            MapReduceChainError::InputEmpty => SummarizeError::EmptyInput,
+            other => SummarizeError::Chain(other.to_string()),
+        }
+    }
+}
+
+impl From<PromptTokensError> for SummarizeError {
+    fn from(e: PromptTokensError) -> Self {
+        SummarizeError::Chain(e.to_string())
+    }
+}
+
+impl ResponseError for SummarizeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SummarizeError::NotConfigured => StatusCode::SERVICE_UNAVAILABLE,
+            SummarizeError::EmptyInput | SummarizeError::Unsupported(_) => StatusCode::BAD_REQUEST,
+            SummarizeError::TooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            SummarizeError::Provider(e) => e.status_code(),
+            SummarizeError::Chain(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SummarizeError::Provider(e) => e.error_response(),
+            other => {
+                HttpResponse::build(other.status_code()).json(json!({ "error": other.to_string() }))
+            }
+        }
+    }
+}
+
+/// Counts and splits text with a tiktoken encoding.
+pub struct BpeTokenizer<'a>(&'a CoreBPE);
+
+impl Tokenizer for BpeTokenizer<'_> {
+    fn tokenize_str(&self, doc: &str) -> Result<TokenCollection, TokenizerError> {
+        Ok(self.0.encode_ordinary(doc).into())
+    }
+
+    fn to_string(&self, tokens: TokenCollection) -> Result<String, TokenizerError> {
+        // Chunk borders can fall inside a multi-byte character; keep the rest of the text.
+        let bytes = self.0._decode_native(&tokens.as_usize()?);
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// Runs llm-chain prompts on one of our providers.
+pub struct ProviderExecutor {
+    provider: Arc<dyn LlmProvider>,
+    bpe: CoreBPE,
+    /// Most tokens a single prompt may use, instructions included.
+    prompt_tokens: usize,
+}
+
+impl ProviderExecutor {
+    pub fn new(provider: Arc<dyn LlmProvider>, prompt_tokens: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            prompt_tokens >= MIN_PROMPT_TOKENS,
+            "summary prompt budget must be at least {} tokens, got {}",
+            MIN_PROMPT_TOKENS,
+            prompt_tokens
+        );
+        Ok(Self {
+            provider,
+            bpe: tiktoken_rs::cl100k_base()?,
+            prompt_tokens,
+        })
+    }
+
+    /// Sends `prompt` to the provider; unlike `execute` this keeps the usage.
+    async fn chat(
+        &self,
+        options: &Options,
+        prompt: &Prompt,
+    ) -> Result<ChatResponse, ExecutorError> {
+        let messages = prompt.to_chat().iter().map(Into::into).collect();
+        let request = ChatRequest::new(messages).with_options(chat_options(options));
+        self.provider
+            .chat(request)
+            .await
+            .map_err(|e| ExecutorError::InnerError(Box::new(e)))
+    }
+}
+
+#[async_trait]
+impl Executor for ProviderExecutor {
+    type StepTokenizer<'a> = BpeTokenizer<'a>;
+
+    fn new_with_options(_options: Options) -> Result<Self, ExecutorCreationError> {
+        Err(ExecutorCreationError::FieldRequiredError(
+            "provider".to_string(),
+        ))
+    }
+
+    async fn execute(&self, options: &Options, prompt: &Prompt) -> Result<Output, ExecutorError> {
+        let response = self.chat(options, prompt).await?;
+        Ok(Output::new_immediate(Data::text(response.content)))
+    }
+
+    fn tokens_used(
+        &self,
+        options: &Options,
+        prompt: &Prompt,
+    ) -> Result<TokenCount, PromptTokensError> {
+        let used = self.bpe.encode_ordinary(&prompt.to_text()).len();
+        Ok(TokenCount::new(
+            self.max_tokens_allowed(options),
+            used as i32,
+        ))
+    }
+
+    fn max_tokens_allowed(&self, _options: &Options) -> i32 {
+        self.prompt_tokens as i32
+    }
+
+    fn answer_prefix(&self, _prompt: &Prompt) -> Option<String> {
+        None
+    }
+
+    fn get_tokenizer(&self, _options: &Options) -> Result<BpeTokenizer<'_>, TokenizerError> {
+        Ok(BpeTokenizer(&self.bpe))
+    }
+}
+
+/// Wraps the shared executor for one request and remembers each answer by prompt,
+/// so the chunk summaries produced inside the chain can be handed back, along
+/// with the tokens all calls used.
+struct RecordingExecutor<'a> {
+    inner: &'a ProviderExecutor,
+    answers: Mutex<HashMap<String, String>>,
+    /// Summed over every call; `None` if the provider never reported usage.
+    usage: Mutex<Option<Usage>>,
+    /// The model the upstream last reported.
+    model: Mutex<Option<String>>,
+}
+
+#[async_trait]
+impl Executor for RecordingExecutor<'_> {
+    type StepTokenizer<'b>
+        = BpeTokenizer<'b>
+    where
+        Self: 'b;
+
+    fn new_with_options(_options: Options) -> Result<Self, ExecutorCreationError> {
+        Err(ExecutorCreationError::FieldRequiredError(
+            "executor".to_string(),
+        ))
+    }
+
+    async fn execute(&self, options: &Options, prompt: &Prompt) -> Result<Output, ExecutorError> {
+        let response = self.inner.chat(options, prompt).await?;
+        {
+            let mut usage = self.usage.lock().unwrap();
+            if let Some(used) = response.usage {
+                *usage.get_or_insert_with(Usage::default) += used;
+            }
+        }
+        if !response.model.is_empty() {
+            *self.model.lock().unwrap() = Some(response.model.clone());
+        }
+        self.answers
+            .lock()
+            .unwrap()
+            .insert(prompt.to_text(), response.content.clone());
+        Ok(Output::new_immediate(Data::text(response.content)))
+    }
+
+    fn tokens_used(
+        &self,
+        options: &Options,
+        prompt: &Prompt,
+    ) -> Result<TokenCount, PromptTokensError> {
+        self.inner.tokens_used(options, prompt)
+    }
+
+    fn max_tokens_allowed(&self, options: &Options) -> i32 {
+        self.inner.max_tokens_allowed(options)
+    }
+
+    fn answer_prefix(&self, prompt: &Prompt) -> Option<String> {
+        self.inner.answer_prefix(prompt)
+    }
+
+    fn get_tokenizer(&self, options: &Options) -> Result<BpeTokenizer<'_>, TokenizerError> {
+        self.inner.get_tokenizer(options)
+    }
+}
+
+#[derive(Debug)]
+pub struct Summary {
+    pub summary: String,
+    pub chunk_count: usize,
+    /// One summary per chunk, in document order; only filled when asked for.
+    pub chunk_summaries: Option<Vec<String>>,
+    /// Tokens used by every map and reduce call together.
+    pub usage: Option<Usage>,
+    /// The model that answered, as reported by the upstream.
+    pub model: Option<String>,
+}
+
+/// The map and reduce prompts plus the executor they run on.
+pub struct Summarizer {
+    executor: ProviderExecutor,
+    map: Step,
+    reduce: Step,
+}
+
+impl Summarizer {
+    pub fn new(executor: ProviderExecutor) -> Self {
+        Self {
+            executor,
+            map: chat_step(MAP_SYSTEM, MAP_USER),
+            reduce: chat_step(REDUCE_SYSTEM, REDUCE_USER),
+        }
+    }
+
+    /// Summarizes `text`, returning the per-chunk summaries too when `with_chunks` is set.
+    pub async fn summarize(
+        &self,
+        text: &str,
+        with_chunks: bool,
+        options: &ChatOptions,
+    ) -> Result<Summary, SummarizeError> {
+        if text.trim().is_empty() {
+            return Err(SummarizeError::EmptyInput);
+        }
+        if options.seed.is_some() {
+            return Err(SummarizeError::Unsupported("seed"));
+        }
+        let step_options = step_options(options);
+        let map = Step::for_prompt_and_options(self.map.prompt().clone(), step_options.clone());
+        let reduce = Step::for_prompt_and_options(self.reduce.prompt().clone(), step_options);
+
+        // The chain splits the document the same way; doing it here first lets
+        // us refuse oversized input before any upstream call is made.
+        let document = Parameters::new_with_text(text);
+        let base = Parameters::new();
+        let chunks = self.executor.split_to_fit(&map, &document, &base, None)?;
+        if chunks.len() > MAX_CHUNKS {
+            return Err(SummarizeError::TooLong {
+                chunks: chunks.len(),
+            });
+        }
+
+        let executor = RecordingExecutor {
+            inner: &self.executor,
+            answers: Mutex::new(HashMap::new()),
+            usage: Mutex::new(None),
+            model: Mutex::new(None),
+        };
+        let chain = Chain::new(map.clone(), reduce);
+        let output = chain.run(vec![document], base.clone(), &executor).await?;
+        let summary = output
+            .to_immediate()
+            .await
+            .map_err(|e| SummarizeError::Chain(e.to_string()))?
+            .as_content()
+            .extract_last_body()
+            .cloned()
+            .unwrap_or_default();
+
+        let chunk_summaries = if with_chunks {
+            let answers = executor.answers.into_inner().unwrap();
+            let summaries = chunks
+                .iter()
+                .map(|chunk| {
+                    let prompt = map
+                        .format(&base.combine(chunk))
+                        .map_err(|e| SummarizeError::Chain(e.to_string()))?;
+                    Ok(answers.get(&prompt.to_text()).cloned().unwrap_or_default())
+                })
+                .collect::<Result<Vec<_>, SummarizeError>>()?;
+            Some(summaries)
+        } else {
+            None
+        };
+
+        Ok(Summary {
+            summary,
+            chunk_count: chunks.len(),
+            chunk_summaries,
+            usage: executor.usage.into_inner().unwrap(),
+            model: executor.model.into_inner().unwrap(),
+        })
+    }
+}
+
+fn chat_step(system: &str, user: &str) -> Step {
+    let messages = ChatMessageCollection::<StringTemplate>::new()
+        .with_system_template(system)
+        .with_user_template(user);
+    Step::for_prompt_template(Data::Chat(messages))
+}
+
+/// Carries request options into the chain's steps.
+fn step_options(options: &ChatOptions) -> Options {
+    let mut builder = Options::builder();
+    if let Some(model) = &options.model {
+        builder.add_option(Opt::Model(ModelRef::from_model_name(model)));
+    }
+    if let Some(temperature) = options.temperature {
+        builder.add_option(Opt::Temperature(temperature as f32));
+    }
+    if let Some(max_tokens) = options.max_tokens {
+        builder.add_option(Opt::MaxTokens(max_tokens as usize));
+    }
+    if let Some(top_p) = options.top_p {
+        builder.add_option(Opt::TopP(top_p as f32));
+    }
+    if let Some(stop) = &options.stop {
+        builder.add_option(Opt::StopSequence(stop.clone()));
+    }
+    builder.build()
+}
+
+/// The inverse of `step_options`, applied when a step reaches the provider.
+fn chat_options(options: &Options) -> ChatOptions {
+    let mut chat = ChatOptions::default();
+    if let Some(Opt::Model(model)) = options.get(OptDiscriminants::Model) {
+        chat.model = Some(model.to_name());
+    }
+    if let Some(Opt::Temperature(temperature)) = options.get(OptDiscriminants::Temperature) {
+        chat.temperature = Some(widen(*temperature));
+    }
+    if let Some(Opt::MaxTokens(max_tokens)) = options.get(OptDiscriminants::MaxTokens) {
+        chat.max_tokens = Some(*max_tokens as u32);
+    }
+    if let Some(Opt::TopP(top_p)) = options.get(OptDiscriminants::TopP) {
+        chat.top_p = Some(widen(*top_p));
+    }
+    if let Some(Opt::StopSequence(stop)) = options.get(OptDiscriminants::StopSequence) {
+        chat.stop = Some(stop.clone());
+    }
+    chat
+}
+
+/// Goes through the shortest decimal form so `0.1f32` becomes `0.1`, not `0.10000000149`.
+fn widen(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or(f64::from(value))
+}
@@ -0,0 +1,270 @@
+//! Structured logs, traces and request ids.
+//!
+//! Every request runs in an `http_request` span carrying its request id: the
+//! client's `X-Request-Id` when it sent a usable one, a fresh UUID otherwise.
+//! The id is returned in the response and forwarded to OpenRouter and Groq,
+//! so one question can be followed from the browser to the upstream. Logs are
+//! written as text or JSON lines; spans can also be exported over OTLP to an
+//! OpenTelemetry collector.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue, USER_AGENT};
+use actix_web::Error;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use serde::Deserialize;
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Instant;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// Identifies one request in logs, traces and upstream calls.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// Accepts a client's id of up to 128 visible ASCII characters.
+    pub fn parse(value: &str) -> Option<Self> {
+        let valid =
+            !value.is_empty() && value.len() <= 128 && value.chars().all(|c| c.is_ascii_graphic());
+        valid.then(|| Self(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The id of the request being handled on this task, if any.
+    pub fn current() -> Option<Self> {
+        REQUEST_ID.try_with(Clone::clone).ok()
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How log lines are written to stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("log format must be text or json, not '{}'", other)),
+        }
+    }
+}
+
+/// Where log lines go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// stdout, logging at `info` by default.
+    Server,
+    /// stderr, logging only warnings by default, so that answers printed by
+    /// the CLI stay clean on stdout.
+    Terminal,
+}
+
+/// Flushes exported spans when dropped at shutdown.
+pub struct TelemetryGuard {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl TelemetryGuard {
+    pub fn exporting(&self) -> bool {
+        self.provider.is_some()
+    }
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                tracing::warn!(error = %e, "failed to flush traces");
+            }
+        }
+    }
+}
+
+/// Whether the standard OTLP endpoint variables ask for span export.
+fn otlp_configured() -> bool {
+    [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|var| std::env::var(var).is_ok_and(|v| !v.trim().is_empty()))
+}
+
+/// Installs the global subscriber.
+///
+/// `RUST_LOG` filters what is logged (default `info` for the server, `warn`
+/// on the terminal). When
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are also exported there over
+/// OTLP/HTTP, e.g. to a local collector at `http://localhost:4318`.
+pub fn init(format: LogFormat, target: LogTarget) -> Result<TelemetryGuard, String> {
+    let default_level = match target {
+        LogTarget::Server => "info",
+        LogTarget::Terminal => "warn",
+    };
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
+
+    let provider = if otlp_configured() {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .map_err(|e| format!("Failed to set up the OTLP exporter: {}", e))?;
+        let mut resource = Resource::builder();
+        if std::env::var("OTEL_SERVICE_NAME").is_err() {
+            resource = resource.with_service_name("rustybot");
+        }
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(resource.build())
+                .build(),
+        )
+    } else {
+        None
+    };
+    let otel = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("rustybot")));
+
+    let output = match (format, target) {
+        (LogFormat::Text, LogTarget::Server) => tracing_subscriber::fmt::layer().boxed(),
+        (LogFormat::Json, LogTarget::Server) => tracing_subscriber::fmt::layer().json().boxed(),
+        (LogFormat::Text, LogTarget::Terminal) => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .boxed(),
+        (LogFormat::Json, LogTarget::Terminal) => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(output)
+        .with(otel)
+        .with(filter)
+        .try_init()
+        .map_err(|e| format!("Failed to set up logging: {}", e))?;
+    Ok(TelemetryGuard { provider })
+}
+
+/// Middleware running each request in a span with its request id, logging
+/// it when the response is ready and returning the id in `X-Request-Id`.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(RequestId::parse)
+            .unwrap_or_else(RequestId::generate);
+        let span = tracing::info_span!(
+            "http_request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.path(),
+            route = req.match_pattern().as_deref().unwrap_or("unmatched"),
+            client_ip = req.connection_info().realip_remote_addr().unwrap_or("-"),
+            user_agent = req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-"),
+        );
+        let service = self.service.clone();
+        let handled = REQUEST_ID.scope(request_id.clone(), async move { service.call(req).await });
+
+        Box::pin(async move {
+            let started = Instant::now();
+            let mut result = handled.instrument(span.clone()).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(error) => error.as_response_error().status_code(),
+            };
+            span.in_scope(|| {
+                tracing::info!(
+                    status = status.as_u16(),
+                    latency_ms = started.elapsed().as_millis() as u64,
+                    "request finished"
+                )
+            });
+            // Errors become responses further out, where the id is no longer known.
+            if let Ok(response) = &mut result {
+                let value = HeaderValue::from_str(request_id.as_str())
+                    .expect("request ids are visible ASCII");
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            result
+        })
+    }
+}
@@ -0,0 +1,474 @@
+//! Named prompt templates loaded from a TOML file at startup.
+//!
+//! ```toml
+//! default = "concise"
+//!
+//! [templates.concise]
+//! description = "Short, direct answers"
+//! system = "You are a helpful assistant. Answer concisely."
+//! user = "{{question}}"
+//!
+//! [templates.translate]
+//! system = "Translate the user's text into {{language}}."
+//! user = "{{question}}"
+//! defaults = { language = "German" }
+//! ```
+//!
+//! Templates use llm-chain's tera syntax. The only variables a template may
+//! use are `question` and the keys of its `defaults` table (which requests can
+//! override); `PromptLibrary::from_toml_str` renders every template once to
+//! reject anything else before the server starts.
+//!
+//! The same file can define multi-step pipelines. Each step is rendered with
+//! the previous step's output as `{{text}}` (llm-chain's sequential chain
+//! convention) and every earlier step's output under that step's name:
+//!
+//! ```toml
+//! [pipelines.refine]
+//! description = "Draft, critique, then answer"
+//! steps = [
+//!     { name = "draft", user = "{{question}}" },
+//!     { name = "critique", user = "Point out mistakes in:\n{{draft}}" },
+//!     { name = "final", user = "Question: {{question}}\nDraft: {{draft}}\nCritique: {{text}}\nWrite the final answer." },
+//! ]
+//! ```
+
+use crate::providers::{ChatMessage, Role};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use llm_chain::prompt::{ChatMessageCollection, Data, StringTemplate};
+use llm_chain::step::Step;
+use llm_chain::Parameters;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+/// Used when no prompt file exists; matches the prompt the server always had.
+const BUILTIN_SYSTEM: &str = "You are a helpful assistant. Answer concisely.";
+const BUILTIN_NAME: &str = "concise";
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(String, std::io::Error),
+    Parse(toml::de::Error),
+    /// A template failed startup validation, e.g. it uses an undeclared variable.
+    Invalid {
+        template: String,
+        reason: String,
+    },
+    UnknownTemplate(String),
+    UnknownPipeline(String),
+    UnknownVariable {
+        template: String,
+        variable: String,
+    },
+    Render(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Io(path, e) => write!(f, "Failed to read {}: {}", path, e),
+            TemplateError::Parse(e) => write!(f, "Invalid prompt template file: {}", e),
+            TemplateError::Invalid { template, reason } => {
+                write!(f, "Template '{}' is invalid: {}", template, reason)
+            }
+            TemplateError::UnknownTemplate(name) => write!(f, "Unknown template '{}'", name),
+            TemplateError::UnknownPipeline(name) => write!(f, "Unknown pipeline '{}'", name),
+            TemplateError::UnknownVariable { template, variable } => {
+                write!(f, "Template '{}' has no variable '{}'", template, variable)
+            }
+            TemplateError::Render(e) => write!(f, "Failed to render prompt: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl ResponseError for TemplateError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TemplateError::UnknownTemplate(_)
+            | TemplateError::UnknownPipeline(_)
+            | TemplateError::UnknownVariable { .. } => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+#[derive(Deserialize)]
+struct TemplateFile {
+    default: String,
+    templates: BTreeMap<String, TemplateDef>,
+    #[serde(default)]
+    pipelines: BTreeMap<String, PipelineDef>,
+}
+
+#[derive(Deserialize)]
+struct PipelineDef {
+    description: Option<String>,
+    steps: Vec<StepDef>,
+    #[serde(default)]
+    defaults: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct StepDef {
+    name: String,
+    system: Option<String>,
+    user: String,
+}
+
+#[derive(Deserialize)]
+struct TemplateDef {
+    description: Option<String>,
+    system: Option<String>,
+    user: String,
+    #[serde(default)]
+    defaults: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub variables: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PipelineInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub steps: Vec<String>,
+    pub variables: Vec<String>,
+}
+
+pub struct PromptTemplate {
+    name: String,
+    description: Option<String>,
+    step: Step,
+    defaults: BTreeMap<String, String>,
+    /// Variables filled in by the caller at render time, e.g. pipeline step outputs.
+    inputs: Vec<String>,
+}
+
+impl PromptTemplate {
+    fn new(name: &str, def: TemplateDef) -> Result<Self, TemplateError> {
+        Self::with_inputs(name, def, Vec::new())
+    }
+
+    fn with_inputs(
+        name: &str,
+        def: TemplateDef,
+        inputs: Vec<String>,
+    ) -> Result<Self, TemplateError> {
+        let bodies = def.system.iter().chain(std::iter::once(&def.user));
+        for variable in bodies.flat_map(|body| referenced_variables(body)) {
+            if variable != "question"
+                && !def.defaults.contains_key(&variable)
+                && !inputs.contains(&variable)
+            {
+                let mut known = vec!["question".to_string()];
+                known.extend(def.defaults.keys().cloned());
+                known.extend(inputs.iter().cloned());
+                return Err(TemplateError::Invalid {
+                    template: name.to_string(),
+                    reason: format!(
+                        "variable '{}' is not available here (known: {})",
+                        variable,
+                        known.join(", ")
+                    ),
+                });
+            }
+        }
+
+        let mut messages = ChatMessageCollection::<StringTemplate>::new();
+        if let Some(system) = def.system {
+            messages = messages.with_system_template(&system);
+        }
+        messages = messages.with_user_template(&def.user);
+
+        let template = Self {
+            name: name.to_string(),
+            description: def.description,
+            step: Step::for_prompt_template(Data::Chat(messages)),
+            defaults: def.defaults,
+            inputs,
+        };
+        template.validate()?;
+        Ok(template)
+    }
+
+    /// Renders once with the defaults to catch tera syntax errors at startup.
+    fn validate(&self) -> Result<(), TemplateError> {
+        let placeholders: Vec<(&str, &str)> =
+            self.inputs.iter().map(|i| (i.as_str(), "")).collect();
+        self.parameters("question", &HashMap::new())
+            .map(|parameters| with_inputs(parameters, &placeholders))
+            .and_then(|parameters| {
+                self.step
+                    .format(&parameters)
+                    .map_err(|e| TemplateError::Render(e.to_string()))
+            })
+            .map(|_| ())
+            .map_err(|e| TemplateError::Invalid {
+                template: self.name.clone(),
+                reason: e.to_string(),
+            })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn info(&self) -> TemplateInfo {
+        TemplateInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            variables: self.defaults.keys().cloned().collect(),
+        }
+    }
+
+    /// Builds the parameters for one request, rejecting variables the template does not declare.
+    pub fn parameters(
+        &self,
+        question: &str,
+        overrides: &HashMap<String, String>,
+    ) -> Result<Parameters, TemplateError> {
+        let mut parameters = Parameters::new().with("question", question);
+        for (key, value) in &self.defaults {
+            parameters = parameters.with(key.as_str(), value.as_str());
+        }
+        for (key, value) in overrides {
+            if !self.defaults.contains_key(key) {
+                return Err(TemplateError::UnknownVariable {
+                    template: self.name.clone(),
+                    variable: key.clone(),
+                });
+            }
+            parameters = parameters.with(key.as_str(), value.as_str());
+        }
+        Ok(parameters)
+    }
+
+    /// Renders the template for `question` into chat messages.
+    pub fn render(
+        &self,
+        question: &str,
+        overrides: &HashMap<String, String>,
+    ) -> Result<Vec<ChatMessage>, TemplateError> {
+        self.render_with(question, overrides, &[])
+    }
+
+    /// Like `render`, additionally filling the template's declared inputs.
+    pub fn render_with(
+        &self,
+        question: &str,
+        overrides: &HashMap<String, String>,
+        inputs: &[(&str, &str)],
+    ) -> Result<Vec<ChatMessage>, TemplateError> {
+        let parameters = with_inputs(self.parameters(question, overrides)?, inputs);
+        let prompt = self
+            .step
+            .format(&parameters)
+            .map_err(|e| TemplateError::Render(e.to_string()))?;
+        Ok(prompt.to_chat().iter().map(Into::into).collect())
+    }
+}
+
+fn with_inputs(parameters: Parameters, inputs: &[(&str, &str)]) -> Parameters {
+    inputs.iter().fold(parameters, |parameters, (key, value)| {
+        if *key == "text" {
+            parameters.with_text(*value)
+        } else {
+            parameters.with(*key, *value)
+        }
+    })
+}
+
+pub struct PipelineStep {
+    pub name: String,
+    pub template: PromptTemplate,
+}
+
+/// A sequence of templates where each step sees the outputs of the ones before it.
+pub struct Pipeline {
+    name: String,
+    description: Option<String>,
+    steps: Vec<PipelineStep>,
+}
+
+impl Pipeline {
+    fn new(name: &str, def: PipelineDef) -> Result<Self, TemplateError> {
+        if def.steps.is_empty() {
+            return Err(TemplateError::Invalid {
+                template: name.to_string(),
+                reason: "pipeline has no steps".to_string(),
+            });
+        }
+
+        let mut inputs = vec!["text".to_string()];
+        let mut steps = Vec::with_capacity(def.steps.len());
+        for step in def.steps {
+            if inputs.contains(&step.name) || step.name == "question" {
+                return Err(TemplateError::Invalid {
+                    template: name.to_string(),
+                    reason: format!("step name '{}' is reserved or used twice", step.name),
+                });
+            }
+            let template_def = TemplateDef {
+                description: None,
+                system: step.system,
+                user: step.user,
+                defaults: def.defaults.clone(),
+            };
+            let label = format!("{}.{}", name, step.name);
+            let template = PromptTemplate::with_inputs(&label, template_def, inputs.clone())?;
+            inputs.push(step.name.clone());
+            steps.push(PipelineStep {
+                name: step.name,
+                template,
+            });
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            description: def.description,
+            steps,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The steps in execution order.
+    pub fn steps(&self) -> &[PipelineStep] {
+        &self.steps
+    }
+
+    pub fn info(&self) -> PipelineInfo {
+        PipelineInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            steps: self.steps.iter().map(|s| s.name.clone()).collect(),
+            variables: self.steps[0].template.defaults.keys().cloned().collect(),
+        }
+    }
+}
+
+pub struct PromptLibrary {
+    default: String,
+    templates: BTreeMap<String, PromptTemplate>,
+    pipelines: BTreeMap<String, Pipeline>,
+}
+
+impl PromptLibrary {
+    /// The single template the server shipped with before prompt files existed.
+    pub fn builtin() -> Self {
+        let def = TemplateDef {
+            description: Some("Short, direct answers".to_string()),
+            system: Some(BUILTIN_SYSTEM.to_string()),
+            user: "{{question}}".to_string(),
+            defaults: BTreeMap::new(),
+        };
+        let template = PromptTemplate::new(BUILTIN_NAME, def).expect("builtin template is valid");
+        Self {
+            default: BUILTIN_NAME.to_string(),
+            templates: BTreeMap::from([(BUILTIN_NAME.to_string(), template)]),
+            pipelines: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TemplateError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| TemplateError::Io(path.display().to_string(), e))?;
+        Self::from_toml_str(&contents)
+    }
+
+    pub fn from_toml_str(contents: &str) -> Result<Self, TemplateError> {
+        let file: TemplateFile = toml::from_str(contents).map_err(TemplateError::Parse)?;
+
+        let templates = file
+            .templates
+            .into_iter()
+            .map(|(name, def)| Ok((name.clone(), PromptTemplate::new(&name, def)?)))
+            .collect::<Result<BTreeMap<_, _>, TemplateError>>()?;
+
+        let pipelines = file
+            .pipelines
+            .into_iter()
+            .map(|(name, def)| Ok((name.clone(), Pipeline::new(&name, def)?)))
+            .collect::<Result<BTreeMap<_, _>, TemplateError>>()?;
+
+        if !templates.contains_key(&file.default) {
+            return Err(TemplateError::Invalid {
+                template: file.default.clone(),
+                reason: "default template is not defined".to_string(),
+            });
+        }
+
+        Ok(Self {
+            default: file.default,
+            templates,
+            pipelines,
+        })
+    }
+
+    /// Looks up `name`, or the default template when `None`.
+    pub fn get(&self, name: Option<&str>) -> Result<&PromptTemplate, TemplateError> {
+        let name = name.unwrap_or(&self.default);
+        self.templates
+            .get(name)
+            .ok_or_else(|| TemplateError::UnknownTemplate(name.to_string()))
+    }
+
+    pub fn pipeline(&self, name: &str) -> Result<&Pipeline, TemplateError> {
+        self.pipelines
+            .get(name)
+            .ok_or_else(|| TemplateError::UnknownPipeline(name.to_string()))
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    pub fn infos(&self) -> Vec<TemplateInfo> {
+        self.templates.values().map(PromptTemplate::info).collect()
+    }
+
+    pub fn pipeline_infos(&self) -> Vec<PipelineInfo> {
+        self.pipelines.values().map(Pipeline::info).collect()
+    }
+}
+
+/// Names referenced as `{{ name }}` / `{{ name | filter }}` in a template body.
+fn referenced_variables(body: &str) -> Vec<String> {
+    body.split("{{")
+        .skip(1)
+        .filter_map(|expr| {
+            let name: String = expr
+                .trim_start_matches('-')
+                .trim_start()
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect();
+            (!name.is_empty()).then_some(name)
+        })
+        .collect()
+}
+
+/// Orders messages as system prompt, then `history`, then the rendered question.
+pub fn with_history(rendered: Vec<ChatMessage>, history: Vec<ChatMessage>) -> Vec<ChatMessage> {
+    let (mut messages, question): (Vec<_>, Vec<_>) =
+        rendered.into_iter().partition(|m| m.role == Role::System);
+    messages.extend(history);
+    messages.extend(question);
+    messages
+}
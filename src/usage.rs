@@ -0,0 +1,112 @@
+//! Token usage and what it costs.
+//!
+//! Prices come from a TOML table in US dollars per million tokens, keyed by
+//! the model id the upstream reports:
+//!
+//! ```toml
+//! [models."meta-llama/llama-3.2-3b-instruct"]
+//! prompt = 0.02
+//! completion = 0.02
+//! ```
+//!
+//! Models without a price still report their token counts; only the cost is
+//! left out. Daily totals per API key are kept by `storage`.
+
+use crate::providers::Usage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// US dollars per million tokens.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriceTable {
+    #[serde(default)]
+    models: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::from_toml_str(&contents)
+            .map_err(|e| format!("Invalid price table {}: {}", path.display(), e))
+    }
+
+    pub fn from_toml_str(contents: &str) -> Result<Self, String> {
+        let table: PriceTable = toml::from_str(contents).map_err(|e| e.to_string())?;
+        if let Some((model, _)) = table
+            .models
+            .iter()
+            .find(|(_, price)| !(price.prompt >= 0.0 && price.completion >= 0.0))
+        {
+            return Err(format!("price of '{}' must not be negative", model));
+        }
+        Ok(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+
+    /// Cost of `usage` on `model` in US dollars, if the model has a price.
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        let price = self.models.get(model)?;
+        Some(
+            (f64::from(usage.prompt_tokens) * price.prompt
+                + f64::from(usage.completion_tokens) * price.completion)
+                / 1_000_000.0,
+        )
+    }
+}
+
+/// Token counts of a request plus their cost when the model has a price.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct UsageReport {
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// Left out unless every call that went into the report has a price.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+}
+
+impl UsageReport {
+    /// Adds up the reports of several calls, one per call.
+    ///
+    /// A call without a report (the upstream sent no usage) leaves the total
+    /// cost unknown; `None` if no call reported usage at all.
+    pub fn total(reports: impl IntoIterator<Item = Option<UsageReport>>) -> Option<UsageReport> {
+        let mut total: Option<UsageReport> = None;
+        let mut complete = true;
+        for report in reports {
+            let Some(report) = report else {
+                complete = false;
+                continue;
+            };
+            total = Some(match total {
+                None => report,
+                Some(mut sum) => {
+                    sum.usage += report.usage;
+                    sum.cost_usd = sum.cost_usd.zip(report.cost_usd).map(|(a, b)| a + b);
+                    sum
+                }
+            });
+        }
+        total.map(|sum| UsageReport {
+            cost_usd: sum.cost_usd.filter(|_| complete),
+            ..sum
+        })
+    }
+}
@@ -0,0 +1,1561 @@
+//! Runs the API against a local stub of the OpenAI-compatible chat API.
+//!
+//! The stub answers `/{provider}/chat/completions` and `/{provider}/models`
+//! for both providers, checking the API key, and picks its behaviour from the
+//! question: `fail-429`, `empty-choices`, `null-choices` and `missing-message`
+//! ask for the matching failure, `slow-answer` answers half a second late,
+//! `echo-request-id` answers with the `X-Request-Id` it was sent, summary
+//! prompts get short summaries (merges count the parts they were given),
+//! requests offering `tools` get a call to the first one, and anything else
+//! gets an answer naming the provider and how many messages it was sent.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+use futures_util::StreamExt;
+use openrouter_rust_demo::app::{self, AppState};
+use openrouter_rust_demo::auth::{hash_key, ApiKeys};
+use openrouter_rust_demo::cache::{DiskBackend, MemoryBackend, ResponseCache};
+use openrouter_rust_demo::cli::{self, Command};
+use openrouter_rust_demo::config::Config;
+use openrouter_rust_demo::conversations::ConversationStore;
+use openrouter_rust_demo::fallback::{FallbackChain, FallbackTarget};
+use openrouter_rust_demo::health::ProviderProbes;
+use openrouter_rust_demo::metrics::{metrics, RequestMetrics};
+use openrouter_rust_demo::params::ParamPolicy;
+use openrouter_rust_demo::providers::{
+    GroqProvider, OpenRouterProvider, ProviderRegistry, RetryPolicy,
+};
+use openrouter_rust_demo::rate_limit::{RateLimits, TrustedProxies};
+use openrouter_rust_demo::storage::{Storage, UsageFilter};
+use openrouter_rust_demo::summarize::{ProviderExecutor, Summarizer};
+use openrouter_rust_demo::telemetry::RequestTracing;
+use openrouter_rust_demo::templates::PromptLibrary;
+use openrouter_rust_demo::usage::PriceTable;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+const STUB_KEY: &str = "stub-key";
+
+async fn stub_chat(
+    http: HttpRequest,
+    provider: web::Path<String>,
+    body: web::Json<Value>,
+) -> HttpResponse {
+    let authorization = http
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok());
+    if authorization != Some(&format!("Bearer {}", STUB_KEY)) {
+        return HttpResponse::Unauthorized()
+            .json(json!({ "error": { "message": "Invalid API key" } }));
+    }
+
+    let messages = body["messages"].as_array().cloned().unwrap_or_default();
+    let question = messages
+        .iter()
+        .rev()
+        .find(|m| m["role"] == "user")
+        .and_then(|m| m["content"].as_str())
+        .unwrap_or_default()
+        .to_string();
+    let model = body["model"].as_str().unwrap_or_default().to_string();
+
+    if question.contains("fail-429") {
+        return HttpResponse::TooManyRequests()
+            .json(json!({ "error": { "message": "Rate limit reached for stub" } }));
+    }
+    if question.contains("echo-request-id") {
+        let id = http
+            .headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok());
+        return answer_with(&model, id.unwrap_or("none"));
+    }
+    if question.contains("slow-answer") {
+        actix_rt::time::sleep(Duration::from_millis(500)).await;
+    }
+    if question.contains("slow-body") {
+        // Headers right away, the rest of the body after the client gave up.
+        let body = futures_util::stream::iter([Ok::<_, actix_web::Error>(
+            bytes::Bytes::from_static(b"{"),
+        )])
+        .chain(futures_util::stream::once(async {
+            actix_rt::time::sleep(Duration::from_secs(2)).await;
+            Ok(bytes::Bytes::from_static(b"}"))
+        }));
+        return HttpResponse::Ok()
+            .content_type("application/json")
+            .streaming(body);
+    }
+    if question.contains("empty-choices") {
+        return HttpResponse::Ok().json(json!({ "model": model, "choices": [] }));
+    }
+    if question.contains("null-choices") {
+        return HttpResponse::Ok().json(json!({ "model": model, "choices": null }));
+    }
+    if question.contains("empty-answer") {
+        return HttpResponse::Ok().json(json!({
+            "model": model,
+            "choices": [{ "message": { "role": "assistant", "content": "" }, "finish_reason": "stop" }],
+        }));
+    }
+    if question.contains("part of a longer document") {
+        return answer_with(&model, "chunk summary");
+    }
+    if question.contains("consecutive parts of a document") {
+        let merged = format!("merged {}", question.matches("chunk summary").count());
+        return answer_with(&model, &merged);
+    }
+    if question.contains("missing-message") {
+        return HttpResponse::Ok()
+            .json(json!({ "model": model, "choices": [{ "finish_reason": "stop" }] }));
+    }
+
+    if let Some(tool) = body["tools"].as_array().and_then(|tools| tools.first()) {
+        let call = json!({
+            "index": 0,
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": tool["function"]["name"], "arguments": "{\"city\":\"Bern\"}" },
+        });
+        if body["stream"] == true {
+            let chunks = [
+                json!({ "model": model, "choices": [{ "delta": { "tool_calls": [call] } }] }),
+                json!({ "model": model, "choices": [{ "delta": {}, "finish_reason": "tool_calls" }] }),
+            ];
+            let mut sse: String = chunks
+                .iter()
+                .map(|chunk| format!("data: {}\n\n", chunk))
+                .collect();
+            sse.push_str("data: [DONE]\n\n");
+            return HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .body(sse);
+        }
+        return HttpResponse::Ok().json(json!({
+            "model": model,
+            "choices": [{
+                "message": { "role": "assistant", "content": null, "tool_calls": [call] },
+                "finish_reason": "tool_calls",
+            }],
+        }));
+    }
+
+    if body["stream"] == true && question.contains("hang-up") {
+        let chunk = json!({ "model": model, "choices": [{ "delta": { "content": "Half" } }] });
+        return HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .body(format!("data: {}\n\n", chunk));
+    }
+
+    let answer = format!("{} got {} messages: {}", provider, messages.len(), question);
+    let usage = json!({ "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 });
+    if body["stream"] == true {
+        let (first, rest) = answer.split_at(answer.len() / 2);
+        let chunks = [
+            json!({ "model": model, "choices": [{ "delta": { "content": first } }] }),
+            json!({ "model": model, "choices": [{ "delta": { "content": rest }, "finish_reason": "stop" }] }),
+            json!({ "model": model, "choices": [], "usage": usage }),
+        ];
+        let mut sse: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .collect();
+        sse.push_str("data: [DONE]\n\n");
+        return HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .body(sse);
+    }
+    answer_with(&model, &answer)
+}
+
+fn answer_with(model: &str, answer: &str) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "model": model,
+        "choices": [{ "message": { "role": "assistant", "content": answer }, "finish_reason": "stop" }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+    }))
+}
+
+async fn stub_models(http: HttpRequest) -> HttpResponse {
+    let authorization = http
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok());
+    if authorization != Some(&format!("Bearer {}", STUB_KEY)) {
+        return HttpResponse::Unauthorized()
+            .json(json!({ "error": { "message": "Invalid API key" } }));
+    }
+    HttpResponse::Ok().json(json!({ "data": [{ "id": "stub/model-a" }, { "id": "stub/model-b" }] }))
+}
+
+/// Starts the stub on a free port and returns its base URL.
+fn start_stub() -> String {
+    let server = HttpServer::new(|| {
+        App::new()
+            .route("/{provider}/chat/completions", web::post().to(stub_chat))
+            .route("/{provider}/models", web::get().to(stub_models))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .expect("stub binds");
+    let address = server.addrs()[0];
+    actix_rt::spawn(server.run());
+    format!("http://{}", address)
+}
+
+/// Upstream errors are asserted on directly, so they are not retried.
+fn no_retries() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 0,
+        ..RetryPolicy::default()
+    }
+}
+
+/// Providers whose key is `None` are registered as disabled.
+fn providers(stub: &str, openrouter_key: Option<&str>, groq_key: Option<&str>) -> ProviderRegistry {
+    let mut providers = ProviderRegistry::new();
+    match openrouter_key {
+        Some(key) => providers.register(Arc::new(
+            OpenRouterProvider::new(key.to_string(), "stub/openrouter-model")
+                .with_base_url(format!("{}/openrouter", stub))
+                .with_retry(no_retries()),
+        )),
+        None => providers.disable("openrouter", "OPENROUTER_API_KEY not set"),
+    }
+    match groq_key {
+        Some(key) => providers.register(Arc::new(
+            GroqProvider::new(key.to_string(), "stub/groq-model")
+                .with_base_url(format!("{}/groq/", stub))
+                .with_retry(no_retries()),
+        )),
+        None => providers.disable("groq", "GROQ_API_KEY not set"),
+    }
+    providers
+}
+
+/// Loads a key file with one key per `(label, extra TOML)` entry; the key
+/// itself is `<label>-key`.
+fn api_keys(name: &str, entries: &[(&str, &str)]) -> Arc<ApiKeys> {
+    let contents: String = entries
+        .iter()
+        .map(|(label, extra)| {
+            let hash = hash_key(&format!("{}-key", label));
+            format!(
+                "[[keys]]\nlabel = \"{}\"\nsha256 = \"{}\"\n{}\n\n",
+                label, hash, extra
+            )
+        })
+        .collect();
+    let path = std::env::temp_dir().join(format!("rustybot-{}-{}.toml", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    Arc::new(ApiKeys::default().load(&path).unwrap())
+}
+
+/// A template with a variable and a three-step pipeline.
+fn prompts() -> Arc<PromptLibrary> {
+    let library = PromptLibrary::from_toml_str(
+        r#"
+        default = "plain"
+
+        [templates.plain]
+        system = "Be brief."
+        user = "{{question}}"
+
+        [templates.translate]
+        user = "Translate into {{language}}: {{question}}"
+        defaults = { language = "German" }
+
+        [pipelines.refine]
+        steps = [
+            { name = "draft", user = "{{question}}" },
+            { name = "critique", user = "Critique: {{draft}}" },
+            { name = "final", user = "Improve [{{draft}}] using [{{text}}]" },
+        ]
+        "#,
+    );
+    Arc::new(library.expect("test prompts are valid"))
+}
+
+fn state(providers: ProviderRegistry) -> AppState {
+    AppState {
+        providers,
+        templates: Arc::new(PromptLibrary::builtin()),
+        conversations: Arc::new(ConversationStore::new(Duration::from_secs(3600))),
+        storage: Arc::new(Storage::open(":memory:").expect("in-memory database opens")),
+        summarizer: None,
+        params: Arc::new(ParamPolicy::new(4096)),
+        fallbacks: Arc::new(FallbackChain::default()),
+        cache: None,
+        prices: Arc::new(PriceTable::default()),
+        probes: Arc::new(ProviderProbes::new(
+            Duration::from_secs(30),
+            Duration::from_secs(5),
+        )),
+        api_keys: Arc::new(ApiKeys::default()),
+        rate_limits: RateLimits::default(),
+        config: Arc::new(Config::default()),
+    }
+}
+
+async fn init(
+    state: AppState,
+) -> impl Service<
+    actix_http::Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = actix_web::Error,
+> {
+    test::init_service(App::new().configure(|cfg| app::configure(cfg, &state))).await
+}
+
+/// Posts `body` to `uri` and returns the status and the JSON answer.
+async fn post_json<S, B>(app: &S, uri: &str, body: Value) -> (StatusCode, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let request = test::TestRequest::post().uri(uri).set_json(body);
+    send(app, request).await
+}
+
+/// Sends `request` and returns the status and the JSON answer.
+async fn send<S, B>(app: &S, request: test::TestRequest) -> (StatusCode, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let request = request.to_request();
+    let uri = request.uri().clone();
+    let response = test::call_service(app, request).await;
+    let status = response.status();
+    let body = test::read_body(response).await;
+    let json = serde_json::from_slice(&body).unwrap_or_else(|e| {
+        panic!(
+            "{} answered {} with non-JSON {:?}: {}",
+            uri, status, body, e
+        )
+    });
+    (status, json)
+}
+
+#[actix_web::test]
+async fn completion_answers_from_openrouter() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let (status, body) =
+        post_json(&app, "/completion", json!({ "question": "What is Rust?" })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    // The builtin template sends a system prompt and the question.
+    assert_eq!(body["answer"], "openrouter got 2 messages: What is Rust?");
+    assert_eq!(body["provider"], "openrouter");
+    assert_eq!(body["model"], "stub/openrouter-model");
+    assert_eq!(body["usage"]["total_tokens"], 15);
+}
+
+#[actix_web::test]
+async fn groqlive_answers_from_groq_with_the_requested_model() {
+    let stub = start_stub();
+    let mut policy = ParamPolicy::new(4096);
+    policy = policy.allow_models("groq", ["stub/other-model".to_string()]);
+    let state = AppState {
+        params: Arc::new(policy),
+        ..state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))
+    };
+    let app = init(state).await;
+
+    let (status, body) = post_json(
+        &app,
+        "/groqlive",
+        json!({ "question": "Hi", "model": "stub/other-model" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["answer"], "groq got 2 messages: Hi");
+    assert_eq!(body["provider"], "groq");
+    assert_eq!(body["model"], "stub/other-model");
+}
+
+#[actix_web::test]
+async fn templates_render_the_question_with_their_variables() {
+    let stub = start_stub();
+    let mut state = state(providers(&stub, Some(STUB_KEY), None));
+    state.templates = prompts();
+    let app = init(state).await;
+
+    for (request, answer) in [
+        (json!({ "question": "Hi" }), "openrouter got 2 messages: Hi"),
+        (
+            json!({ "question": "Hi", "template": "translate" }),
+            "openrouter got 1 messages: Translate into German: Hi",
+        ),
+        (
+            json!({ "question": "Hi", "template": "translate", "variables": { "language": "French" } }),
+            "openrouter got 1 messages: Translate into French: Hi",
+        ),
+    ] {
+        let (status, body) = post_json(&app, "/completion", request).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["answer"], answer);
+    }
+
+    for (request, error) in [
+        (
+            json!({ "question": "Hi", "template": "nope" }),
+            "Unknown template 'nope'",
+        ),
+        (
+            json!({ "question": "Hi", "template": "translate", "variables": { "tone": "dry" } }),
+            "Template 'translate' has no variable 'tone'",
+        ),
+    ] {
+        let (status, body) = post_json(&app, "/completion", request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], error);
+    }
+
+    let (status, body) = send(&app, test::TestRequest::get().uri("/templates")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["default"], "plain");
+    assert_eq!(body["templates"][1]["variables"], json!(["language"]));
+    assert_eq!(
+        body["pipelines"][0]["steps"],
+        json!(["draft", "critique", "final"])
+    );
+}
+
+#[actix_web::test]
+async fn pipelines_feed_each_step_the_earlier_outputs() {
+    let stub = start_stub();
+    let mut state = state(providers(&stub, Some(STUB_KEY), None));
+    state.templates = prompts();
+    let app = init(state).await;
+
+    let (status, body) = post_json(
+        &app,
+        "/completion",
+        json!({ "question": "Hi", "mode": "refine", "debug": true }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let draft = "openrouter got 1 messages: Hi";
+    let critique = format!("openrouter got 1 messages: Critique: {}", draft);
+    assert_eq!(
+        body["steps"][0],
+        json!({ "name": "draft", "output": draft })
+    );
+    assert_eq!(
+        body["steps"][1],
+        json!({ "name": "critique", "output": critique })
+    );
+    assert_eq!(
+        body["answer"],
+        format!(
+            "openrouter got 1 messages: Improve [{}] using [{}]",
+            draft, critique
+        )
+    );
+    assert_eq!(body["usage"]["total_tokens"], 45, "every step is counted");
+
+    let (status, body) = post_json(
+        &app,
+        "/completion",
+        json!({ "question": "Hi", "mode": "refine" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body.get("steps").is_none(), "{}", body);
+
+    for request in [
+        json!({ "question": "Hi", "mode": "nope" }),
+        json!({ "question": "Hi", "mode": "refine", "template": "plain" }),
+    ] {
+        let request = test::TestRequest::post()
+            .uri("/completion")
+            .set_json(request)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[actix_web::test]
+async fn repeated_questions_are_answered_from_the_cache() {
+    let stub = start_stub();
+    let mut state = state(providers(&stub, Some(STUB_KEY), None));
+    state.cache = Some(Arc::new(ResponseCache::new(
+        Duration::from_secs(60),
+        MemoryBackend::new(16),
+    )));
+    let app = init(state).await;
+    let ask = |question: &str, cache_control: Option<&str>| {
+        let mut request = test::TestRequest::post()
+            .uri("/completion")
+            .set_json(json!({ "question": question }));
+        if let Some(value) = cache_control {
+            request = request.insert_header(("cache-control", value.to_string()));
+        }
+        send(&app, request)
+    };
+    let cached = |body: &Value| body["cached"] == true;
+
+    let (status, body) = ask("What is Rust?", None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(!cached(&body), "{}", body);
+    let (_, body) = ask("  what IS   rust? ", None).await;
+    assert!(cached(&body), "{}", body);
+    assert_eq!(body["answer"], "openrouter got 2 messages: What is Rust?");
+    assert_eq!(body["provider"], "openrouter");
+    let (_, body) = ask("What is Rust?", Some("no-cache")).await;
+    assert!(!cached(&body), "{}", body);
+
+    // no-store neither reads nor fills the cache.
+    let (_, body) = ask("What is Go?", Some("no-store")).await;
+    assert!(!cached(&body), "{}", body);
+    let (_, body) = ask("What is Go?", None).await;
+    assert!(!cached(&body), "{}", body);
+    let (_, body) = ask("What is Go?", None).await;
+    assert!(cached(&body), "{}", body);
+
+    // Placeholders for empty answers are never cached.
+    for _ in 0..2 {
+        let (_, body) = ask("empty-answer", None).await;
+        assert!(!cached(&body), "{}", body);
+    }
+}
+
+#[actix_web::test]
+async fn the_disk_cache_survives_a_restart() {
+    let stub = start_stub();
+    let dir = std::env::temp_dir().join(format!("rustybot-{}-cache", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let disk_state = || {
+        let mut state = state(providers(&stub, Some(STUB_KEY), None));
+        state.cache = Some(Arc::new(ResponseCache::new(
+            Duration::from_secs(60),
+            DiskBackend::open(&dir).unwrap(),
+        )));
+        state
+    };
+    let question = json!({ "question": "What is Rust?" });
+
+    let app = init(disk_state()).await;
+    let (_, body) = post_json(&app, "/completion", question.clone()).await;
+    assert!(body.get("cached").is_none(), "{}", body);
+    let app = init(disk_state()).await;
+    let (_, body) = post_json(&app, "/completion", question).await;
+    assert_eq!(body["cached"], true, "{}", body);
+}
+
+#[actix_web::test]
+async fn completion_stream_relays_chunks_as_sse() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let request = test::TestRequest::post()
+        .uri("/completion/stream")
+        .set_json(json!({ "question": "Stream please" }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+
+    let events: Vec<(&str, Value)> = body
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| {
+            let (name, data) = event.split_once('\n').expect("event and data lines");
+            let name = name.strip_prefix("event: ").expect("named event");
+            let data = data.strip_prefix("data: ").expect("data line");
+            (name, serde_json::from_str(data).expect("JSON data"))
+        })
+        .collect();
+    let answer: String = events
+        .iter()
+        .filter(|(name, _)| *name == "delta")
+        .map(|(_, data)| data["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(answer, "openrouter got 2 messages: Stream please");
+
+    let (name, done) = events.last().expect("at least one event");
+    assert_eq!(*name, "done", "{}", body);
+    assert_eq!(done["finish_reason"], "stop");
+    assert_eq!(done["usage"]["total_tokens"], 15);
+}
+
+#[actix_web::test]
+async fn streams_cut_off_before_the_end_report_an_error() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let request = test::TestRequest::post()
+        .uri("/completion/stream")
+        .set_json(json!({ "question": "hang-up" }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(body.starts_with("event: delta\n"), "{}", body);
+    assert!(!body.contains("event: done"), "{}", body);
+    let last = body.trim_end().rsplit("\n\n").next().unwrap();
+    assert!(last.starts_with("event: error\n"), "{}", body);
+    assert!(
+        last.contains("stream ended before the answer was finished"),
+        "{}",
+        body
+    );
+}
+
+#[actix_web::test]
+async fn request_ids_are_returned_and_forwarded_upstream() {
+    let stub = start_stub();
+    let state = state(providers(&stub, Some(STUB_KEY), None));
+    let app = test::init_service(
+        App::new()
+            .wrap(RequestTracing)
+            .configure(|cfg| app::configure(cfg, &state)),
+    )
+    .await;
+    let ask = |request_id: Option<&str>| {
+        let mut request = test::TestRequest::post()
+            .uri("/completion")
+            .set_json(json!({ "question": "echo-request-id" }));
+        if let Some(id) = request_id {
+            request = request.insert_header(("x-request-id", id.to_string()));
+        }
+        let app = &app;
+        async move {
+            let response = test::call_service(app, request.to_request()).await;
+            let returned = response.headers().get("x-request-id").unwrap();
+            let returned = returned.to_str().unwrap().to_string();
+            let body: Value = test::read_body_json(response).await;
+            (returned, body["answer"].as_str().unwrap().to_string())
+        }
+    };
+
+    let (returned, forwarded) = ask(Some("trace-42")).await;
+    assert_eq!(returned, "trace-42");
+    assert_eq!(forwarded, "trace-42");
+
+    // Missing or unusable ids are replaced with a fresh UUID.
+    let too_long = "x".repeat(129);
+    for id in [None, Some("has spaces"), Some(too_long.as_str())] {
+        let (returned, forwarded) = ask(id).await;
+        assert!(
+            uuid::Uuid::parse_str(&returned).is_ok(),
+            "{:?}: {}",
+            id,
+            returned
+        );
+        assert_eq!(forwarded, returned);
+    }
+}
+
+#[actix_web::test]
+async fn metrics_count_requests_upstream_calls_and_cache_lookups() {
+    let stub = start_stub();
+    metrics().label_models("groq", vec!["stub/groq-model".to_string()]);
+    let mut state = AppState {
+        params: Arc::new(ParamPolicy::new(4096).allow_models("groq", ["*".to_string()])),
+        ..state(providers(&stub, None, Some(STUB_KEY)))
+    };
+    state.cache = Some(Arc::new(ResponseCache::new(
+        Duration::from_secs(60),
+        MemoryBackend::new(16),
+    )));
+    let app = test::init_service(
+        App::new()
+            .wrap(RequestMetrics)
+            .configure(|cfg| app::configure(cfg, &state)),
+    )
+    .await;
+    // The registry is shared with tests running alongside, so compare counts
+    // before and after instead of expecting exact values.
+    let scrape = || async {
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        let content_type = response.headers().get("content-type").unwrap();
+        assert!(content_type.to_str().unwrap().starts_with("text/plain"));
+        String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+    };
+    let value = |text: &str, series: &str| -> f64 {
+        text.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .map_or(0.0, |value| value.parse().unwrap())
+    };
+    let groqlive = r#"{route="/groqlive",method="POST",status="200"}"#;
+    let answered = r#"{provider="groq",model="stub/groq-model",status="200"}"#;
+    let failed = r#"{provider="groq",model="stub/groq-model"}"#;
+    let hits = r#"{result="hit"}"#;
+
+    let before = scrape().await;
+    for question in ["metrics question", "metrics question", "fail-429"] {
+        post_json(&app, "/groqlive", json!({ "question": question })).await;
+    }
+    let (status, _) = post_json(
+        &app,
+        "/groqlive",
+        json!({ "question": "Hi", "model": "stub/unlisted-metrics-model" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let request = test::TestRequest::get().uri("/conversations/not-a-conversation");
+    test::call_service(&app, request.to_request()).await;
+    let after = scrape().await;
+
+    let requests = "rustybot_http_requests_total";
+    let increase = |series: &str| value(&after, series) - value(&before, series);
+    assert_eq!(
+        increase(&format!("{}{}", requests, groqlive)),
+        3.0,
+        "{}",
+        after
+    );
+    // Routes are labelled with their pattern, not the path.
+    assert!(after.contains(r#"{route="/conversations/{id}",method="GET",status="404"}"#));
+    assert!(!after.contains("not-a-conversation"));
+    // The cached repeat never reached the upstream.
+    let upstream = "rustybot_upstream_requests_total";
+    assert!(
+        increase(&format!("{}{}", upstream, answered)) >= 1.0,
+        "{}",
+        after
+    );
+    assert!(increase(&format!("rustybot_upstream_errors_total{}", failed)) >= 1.0);
+    assert!(increase(&format!("rustybot_cache_lookups_total{}", hits)) >= 1.0);
+    // Models outside the configured list are not named.
+    assert!(!after.contains("stub/unlisted-metrics-model"), "{}", after);
+    assert!(after.contains(
+        r#"rustybot_upstream_requests_total{provider="groq",model="other",status="200"}"#
+    ));
+    assert!(after.contains(r#"rustybot_tokens_total{provider="groq",model="other",kind="prompt"}"#));
+}
+
+#[actix_web::test]
+async fn conversations_replay_history_to_the_provider() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let (status, created) = post_json(&app, "/conversations", json!({ "provider": "groq" })).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", created);
+    let id = created["conversation_id"].as_str().unwrap();
+
+    let uri = format!("/conversations/{}/messages", id);
+    let (status, first) = post_json(&app, &uri, json!({ "question": "One" })).await;
+    assert_eq!(status, StatusCode::OK, "{}", first);
+    assert_eq!(first["answer"], "groq got 2 messages: One");
+
+    // System prompt, the first turn, then the new question.
+    let (_, second) = post_json(&app, &uri, json!({ "question": "Two" })).await;
+    assert_eq!(second["answer"], "groq got 4 messages: Two");
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/conversations/{}", id))
+        .to_request();
+    let record: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(record["messages"].as_array().unwrap().len(), 4);
+}
+
+#[actix_web::test]
+async fn upstream_errors_pass_through_with_their_status() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let (status, body) = post_json(&app, "/groqlive", json!({ "question": "fail-429" })).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let error = body["error"].as_str().unwrap();
+    assert!(error.contains("Rate limit reached for stub"), "{}", error);
+}
+
+#[actix_web::test]
+async fn a_body_that_arrives_too_late_is_a_timeout() {
+    let stub = start_stub();
+    let mut providers = providers(&stub, Some(STUB_KEY), None);
+    providers.register(Arc::new(
+        GroqProvider::new(STUB_KEY.to_string(), "stub/groq-model")
+            .with_base_url(format!("{}/groq/", stub))
+            .with_timeout(Duration::from_millis(300))
+            .with_retry(no_retries()),
+    ));
+    let app = init(state(providers)).await;
+
+    let (status, body) = post_json(&app, "/groqlive", json!({ "question": "slow-body" })).await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT, "{}", body);
+    assert_eq!(body["error"], "Upstream timed out");
+}
+
+#[actix_web::test]
+async fn a_rejected_key_passes_through_as_unauthorized() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some("wrong-key"), Some(STUB_KEY)))).await;
+
+    let (status, body) = post_json(&app, "/completion", json!({ "question": "Hi" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(
+        body["error"].as_str().unwrap().contains("Invalid API key"),
+        "{}",
+        body
+    );
+}
+
+#[actix_web::test]
+async fn malformed_choices_are_reported_as_server_errors() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    for (question, expected) in [
+        ("empty-choices", "no choices in response"),
+        ("null-choices", "Failed to parse response"),
+        ("missing-message", "Failed to parse response"),
+    ] {
+        let (status, body) = post_json(&app, "/completion", json!({ "question": question })).await;
+        assert_eq!(
+            status,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "{}: {}",
+            question,
+            body
+        );
+        let error = body["error"].as_str().unwrap();
+        assert!(error.contains(expected), "{}: {}", question, error);
+    }
+}
+
+#[actix_web::test]
+async fn providers_without_a_key_answer_503_and_the_rest_keep_working() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, None, Some(STUB_KEY)))).await;
+
+    let (status, body) = post_json(&app, "/completion", json!({ "question": "Hi" })).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(
+        body["error"]
+            .as_str()
+            .unwrap()
+            .contains("OPENROUTER_API_KEY"),
+        "{}",
+        body
+    );
+
+    let (status, _) = post_json(&app, "/groqlive", json!({ "question": "Hi" })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let request = test::TestRequest::get().uri("/providers").to_request();
+    let listed: Value = test::call_and_read_body_json(&app, request).await;
+    let openrouter = listed["providers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|p| p["name"] == "openrouter")
+        .expect("openrouter is listed");
+    assert_eq!(openrouter["enabled"], false);
+    assert_eq!(openrouter["reason"], "OPENROUTER_API_KEY not set");
+}
+
+#[actix_web::test]
+async fn readiness_needs_one_answering_provider() {
+    let stub = start_stub();
+    let ready = |providers: ProviderRegistry| async move {
+        let app = init(state(providers)).await;
+        send(&app, test::TestRequest::get().uri("/readyz")).await
+    };
+
+    let app = init(state(providers(&stub, None, None))).await;
+    let (status, body) = send(&app, test::TestRequest::get().uri("/healthz")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "status": "ok" }));
+
+    let (status, body) = ready(providers(&stub, Some(STUB_KEY), None)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body.get("degraded").is_none(), "{}", body);
+    let checks = &body["checks"];
+    assert_eq!(
+        checks["provider:openrouter"]["detail"],
+        "2 models available"
+    );
+    assert_eq!(checks["provider:groq"]["status"], "disabled");
+    for check in ["config", "static", "storage"] {
+        assert_eq!(checks[check]["status"], "ok", "{}", body);
+    }
+
+    // One provider failing leaves the other serving traffic.
+    let (status, body) = ready(providers(&stub, Some(STUB_KEY), Some("wrong-key"))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["degraded"], true);
+    assert_eq!(body["checks"]["provider:groq"]["status"], "failed");
+
+    let (status, body) = ready(providers(&stub, Some("wrong-key"), Some("wrong-key"))).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    assert_eq!(body["ready"], false);
+
+    let (status, body) = ready(providers(&stub, None, None)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    assert_eq!(body["checks"]["config"]["status"], "failed");
+}
+
+#[actix_web::test]
+async fn conversations_are_private_to_the_key_that_started_them() {
+    let stub = start_stub();
+    let mut state = state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)));
+    state.api_keys = api_keys("owners", &[("alice", ""), ("bob", "")]);
+    let app = init(state).await;
+    let as_key = |request: test::TestRequest, key: &str| {
+        send(
+            &app,
+            request.insert_header(("authorization", format!("Bearer {}-key", key))),
+        )
+    };
+
+    let (status, body) = as_key(
+        test::TestRequest::post()
+            .uri("/conversations")
+            .set_json(json!({})),
+        "alice",
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let id = body["conversation_id"].as_str().unwrap().to_string();
+    let conversation = format!("/conversations/{}", id);
+    let message = json!({ "question": "Hi" });
+
+    let (_, body) = as_key(test::TestRequest::get().uri("/conversations"), "bob").await;
+    assert_eq!(body["conversations"], json!([]), "{}", body);
+    for request in [
+        test::TestRequest::get().uri(&conversation),
+        test::TestRequest::post()
+            .uri(&format!("{}/messages", conversation))
+            .set_json(&message),
+        test::TestRequest::delete().uri(&conversation),
+    ] {
+        let (status, body) = as_key(request, "bob").await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+    }
+
+    let (status, body) = as_key(
+        test::TestRequest::post()
+            .uri(&format!("{}/messages", conversation))
+            .set_json(&message),
+        "alice",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_, body) = as_key(test::TestRequest::get().uri("/conversations"), "alice").await;
+    assert_eq!(body["conversations"][0]["id"], id.as_str(), "{}", body);
+    assert_eq!(body["conversations"][0]["message_count"], 2, "{}", body);
+}
+
+#[actix_web::test]
+async fn turns_finishing_after_eviction_or_deletion_are_kept_or_refused() {
+    let stub = start_stub();
+    let state = state(providers(&stub, Some(STUB_KEY), None));
+    let conversations = state.conversations.clone();
+    let app = &init(state).await;
+    let start = || async {
+        let (_, body) = post_json(app, "/conversations", json!({})).await;
+        body["conversation_id"].as_str().unwrap().to_string()
+    };
+    let slow_message = |uri: String| async move {
+        post_json(app, &uri, json!({ "question": "slow-answer" })).await
+    };
+
+    // Evicted while the answer was on the way: stored, and back in memory.
+    let id = start().await;
+    let ((status, body), _) = futures_util::join!(
+        slow_message(format!("/conversations/{}/messages", id)),
+        async {
+            actix_rt::time::sleep(Duration::from_millis(100)).await;
+            conversations.remove(&id);
+        }
+    );
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(conversations.get(&id).expect("restored").turns.len(), 1);
+
+    // Deleted while the answer was on the way: gone.
+    let id = start().await;
+    let ((status, body), deleted) = futures_util::join!(
+        slow_message(format!("/conversations/{}/messages", id)),
+        async {
+            actix_rt::time::sleep(Duration::from_millis(100)).await;
+            let uri = format!("/conversations/{}", id);
+            let request = test::TestRequest::delete().uri(&uri).to_request();
+            test::call_service(app, request).await.status()
+        }
+    );
+    assert_eq!(deleted, StatusCode::NO_CONTENT);
+    assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+    assert!(conversations.get(&id).is_none());
+}
+
+#[actix_web::test]
+async fn conversations_need_an_enabled_provider() {
+    let app = init(state(providers("http://127.0.0.1:9", None, None))).await;
+
+    let (status, _) = post_json(&app, "/conversations", json!({ "provider": "groq" })).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let (status, _) = post_json(&app, "/conversations", json!({ "provider": "nope" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn summarize_without_a_summarizer_answers_503() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), None))).await;
+
+    let (status, body) = post_json(&app, "/summarize", json!({ "text": "Some text" })).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body, json!({ "error": "summarizer not configured" }));
+}
+
+#[actix_web::test]
+async fn summarize_splits_long_text_into_chunks_and_merges_them() {
+    let stub = start_stub();
+    let providers = providers(&stub, Some(STUB_KEY), None);
+    let executor = ProviderExecutor::new(providers.get("openrouter").unwrap(), 256).unwrap();
+    let mut state = state(providers);
+    state.summarizer = Some(Arc::new(Summarizer::new(executor)));
+    let app = init(state).await;
+
+    let text = "The quick brown fox jumps over the lazy dog. ".repeat(60);
+    let (status, body) = post_json(
+        &app,
+        "/summarize",
+        json!({ "text": text, "include_chunks": true }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let chunks = body["chunk_count"].as_u64().unwrap();
+    assert!(chunks > 1, "{}", body);
+    assert_eq!(
+        body["chunk_summaries"].as_array().unwrap().len() as u64,
+        chunks
+    );
+    assert_eq!(body["chunk_summaries"][0], "chunk summary");
+    assert_eq!(body["summary"], format!("merged {}", chunks));
+    assert_eq!(body["usage"]["total_tokens"], 15 * (chunks + 1));
+
+    let (status, body) = post_json(&app, "/summarize", json!({ "text": "Short." })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["chunk_count"], 1);
+    assert!(body.get("chunk_summaries").is_none(), "{}", body);
+
+    let text = "The quick brown fox jumps over the lazy dog. ".repeat(2000);
+    let (status, body) = post_json(&app, "/summarize", json!({ "text": text })).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{}", body);
+    let (status, _) = post_json(&app, "/summarize", json!({ "text": "  " })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn fallbacks_skip_models_the_key_or_the_policy_does_not_allow() {
+    let stub = start_stub();
+    let mut providers = providers(&stub, Some(STUB_KEY), None);
+    providers.register(Arc::new(
+        GroqProvider::new(STUB_KEY.to_string(), "stub/groq-model")
+            .with_base_url("http://127.0.0.1:9")
+            .with_retry(no_retries()),
+    ));
+    let mut state = state(providers);
+    state.fallbacks = Arc::new(
+        FallbackChain::new(
+            vec![
+                "openrouter:not-in-the-policy".parse().unwrap(),
+                FallbackTarget {
+                    provider: "openrouter".to_string(),
+                    model: None,
+                },
+            ],
+            &state.providers,
+        )
+        .unwrap(),
+    );
+    state.api_keys = api_keys(
+        "fallbacks",
+        &[("groq-only", "models = [\"stub/groq-model\"]"), ("any", "")],
+    );
+    let app = init(state).await;
+    let ask = |key: &str| {
+        let request = test::TestRequest::post()
+            .uri("/groqlive")
+            .insert_header(("authorization", format!("Bearer {}", key)))
+            .set_json(json!({ "question": "Hi" }));
+        send(&app, request)
+    };
+
+    // Groq is unreachable and the key may not use OpenRouter's model.
+    let (status, body) = ask("groq-only-key").await;
+    assert!(status.is_server_error(), "{}", body);
+    assert!(!body.to_string().contains("openrouter"), "{}", body);
+
+    // The policy only allows OpenRouter's default model.
+    let (status, body) = ask("any-key").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["provider"], "openrouter");
+    assert_eq!(body["model"], "stub/openrouter-model");
+    assert_eq!(
+        body["failed_attempts"].as_array().unwrap().len(),
+        1,
+        "{}",
+        body
+    );
+}
+
+#[actix_web::test]
+async fn usage_totals_show_other_keys_only_to_admin_keys() {
+    let mut state = state(providers("http://127.0.0.1:9", None, None));
+    state.api_keys = api_keys(
+        "admin",
+        &[
+            ("root", "admin = true"),
+            ("website", "routes = [\"/admin\"]"),
+        ],
+    );
+    for label in ["root", "website", "website"] {
+        state
+            .storage
+            .record_usage(label, "groq", "stub/groq-model", None, None)
+            .unwrap();
+    }
+    let app = init(state).await;
+    let usage = |key: &str, query: &str| {
+        let request = test::TestRequest::get()
+            .uri(&format!("/admin/usage{}", query))
+            .insert_header(("authorization", format!("Bearer {}", key)));
+        send(&app, request)
+    };
+
+    let (status, body) = usage("root-key", "").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["usage"].as_array().unwrap().len(), 2, "{}", body);
+    let (_, body) = usage("root-key", "?api_key=website").await;
+    assert_eq!(body["usage"][0]["requests"], 2, "{}", body);
+
+    // Listing /admin in a key's routes is not enough to see the others.
+    for query in ["", "?api_key=root"] {
+        let (status, body) = usage("website-key", query).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let rows = body["usage"].as_array().unwrap();
+        assert_eq!(rows.len(), 1, "{}", body);
+        assert_eq!(rows[0]["api_key"], "website");
+    }
+}
+
+#[actix_web::test]
+async fn cli_pipelines_record_the_usage_of_every_step() {
+    let stub = start_stub();
+    let mut state = state(providers(&stub, Some(STUB_KEY), None));
+    state.templates = prompts();
+    let Ok(Command::Ask(args)) =
+        Command::parse(["ask", "--mode", "refine", "--no-stream", "Hi"].map(String::from))
+    else {
+        panic!("not an ask command");
+    };
+
+    cli::run_ask(&state, args).await.unwrap();
+    let totals = state.storage.usage_totals(&UsageFilter::default()).unwrap();
+    assert_eq!(totals.len(), 1, "{:?}", totals);
+    assert_eq!(totals[0].api_key, "cli");
+    assert_eq!(totals[0].requests, 3);
+    assert_eq!(totals[0].total_tokens, 45);
+}
+
+#[actix_web::test]
+async fn rate_limits_count_forwarded_clients_only_behind_trusted_proxies() {
+    let mut state = state(providers("http://127.0.0.1:9", None, None));
+    state.rate_limits = RateLimits::new([("/completion", "1/60")])
+        .unwrap()
+        .with_trusted_proxies(TrustedProxies::parse(&["10.0.0.0/8"]).unwrap());
+    let app = init(state).await;
+    let status = |peer: &str, forwarded: &str| {
+        let request = test::TestRequest::post()
+            .uri("/completion")
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("x-forwarded-for", forwarded.to_string()))
+            .set_json(json!({ "question": "Hi" }))
+            .to_request();
+        let app = &app;
+        async move { test::call_service(app, request).await.status() }
+    };
+
+    // Direct clients cannot pick a fresh bucket by forging the header.
+    assert_eq!(
+        status("203.0.113.1:1000", "1.1.1.1").await,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(
+        status("203.0.113.1:1001", "2.2.2.2").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // Behind the proxy, only the right-most untrusted hop counts.
+    assert_eq!(
+        status("10.0.0.1:1000", "1.1.1.1, 198.51.100.7, 10.0.0.2").await,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(
+        status("10.0.0.1:1001", "2.2.2.2, 198.51.100.7").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(
+        status("10.0.0.1:1002", "198.51.100.8").await,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[actix_web::test]
+async fn greeter_routes() {
+    let app = init(state(providers("http://127.0.0.1:9", None, None))).await;
+
+    let request = test::TestRequest::get().uri("/name").to_request();
+    assert_eq!(
+        test::call_and_read_body(&app, request).await,
+        "Hello, world!"
+    );
+
+    let request = test::TestRequest::get().uri("/name/Ferris").to_request();
+    assert_eq!(
+        test::call_and_read_body(&app, request).await,
+        "Hello, Ferris!"
+    );
+}
+
+/// Reads an OpenAI-style stream into its JSON chunks, checking it ends with `[DONE]`.
+fn openai_chunks(body: &str) -> Vec<Value> {
+    let data: Vec<&str> = body
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| event.strip_prefix("data: ").expect("data line"))
+        .collect();
+    assert_eq!(data.last(), Some(&"[DONE]"), "{}", body);
+    data[..data.len() - 1]
+        .iter()
+        .map(|chunk| serde_json::from_str(chunk).expect("JSON chunk"))
+        .collect()
+}
+
+#[actix_web::test]
+async fn openai_chat_completions_route_by_model() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({
+            "model": "groq/stub/groq-model",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": [{ "type": "text", "text": "Hi" }] },
+            ],
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["object"], "chat.completion");
+    assert_eq!(body["model"], "groq/stub/groq-model");
+    assert_eq!(body["choices"][0]["message"]["role"], "assistant");
+    // Sent as is: no template unless one is asked for.
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        "groq got 2 messages: Hi"
+    );
+    assert_eq!(body["choices"][0]["finish_reason"], "stop");
+    assert_eq!(body["usage"]["total_tokens"], 15);
+
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({
+            "model": "openrouter",
+            "template": "concise",
+            "messages": [{ "role": "user", "content": "Hi" }],
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["model"], "openrouter/stub/openrouter-model");
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        "openrouter got 2 messages: Hi"
+    );
+
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({ "model": "groq/unknown-model", "messages": [{ "role": "user", "content": "Hi" }] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert!(
+        body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("not allowed"),
+        "{}",
+        body
+    );
+
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({ "messages": [{ "role": "critic", "content": "Hi" }] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(
+        body["error"],
+        json!({
+            "message": "Unsupported message role 'critic'",
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": null,
+        })
+    );
+}
+
+#[actix_web::test]
+async fn openai_model_ids_that_name_their_provider_are_not_prefixed_twice() {
+    let stub = start_stub();
+    let mut providers = ProviderRegistry::new();
+    providers.register(Arc::new(
+        GroqProvider::new(STUB_KEY.to_string(), "groq/compound-mini")
+            .with_base_url(format!("{}/groq", stub))
+            .with_retry(no_retries()),
+    ));
+    providers.disable("openrouter", "OPENROUTER_API_KEY not set");
+    let app = init(state(providers)).await;
+
+    let (status, body) = send(&app, test::TestRequest::get().uri("/v1/models")).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"][0]["id"], "groq/compound-mini");
+    assert!(body["data"][0]["created"].as_u64().unwrap() > 1_700_000_000);
+
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({
+            "model": "groq/compound-mini",
+            "messages": [{ "role": "user", "content": "Hi" }],
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["model"], "groq/compound-mini");
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        "groq got 1 messages: Hi"
+    );
+}
+
+#[actix_web::test]
+async fn openai_passes_empty_answers_through() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({ "messages": [{ "role": "user", "content": "empty-answer" }] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["choices"][0]["message"]["content"], "");
+
+    let (status, body) =
+        post_json(&app, "/completion", json!({ "question": "empty-answer" })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["answer"], "No answer received");
+}
+
+#[actix_web::test]
+async fn openai_errors_from_middleware_use_the_openai_error_object() {
+    let stub = start_stub();
+    let mut state = state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)));
+    state.api_keys = api_keys("openai-errors", &[("client", "")]);
+    state.rate_limits = RateLimits::new([("/v1/chat/completions", "1/60")]).unwrap();
+    let app = init(state).await;
+    let ask = |key: Option<&str>, question: &str| {
+        let mut request = test::TestRequest::post()
+            .uri("/v1/chat/completions")
+            .set_json(json!({ "messages": [{ "role": "user", "content": question }] }));
+        if let Some(key) = key {
+            request = request.insert_header(("authorization", format!("Bearer {}", key)));
+        }
+        send(&app, request)
+    };
+
+    let (status, body) = ask(None, "Hi").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+    assert_eq!(body["error"]["type"], "authentication_error");
+    assert_eq!(body["error"]["code"], "invalid_api_key");
+    assert!(body["error"]["message"].is_string(), "{}", body);
+
+    // The upstream's own message, not its raw body.
+    let (status, body) = ask(Some("client-key"), "fail-429").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", body);
+    assert_eq!(body["error"]["message"], "Rate limit reached for stub");
+
+    let (status, body) = ask(Some("client-key"), "Hi").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", body);
+    assert_eq!(body["error"]["type"], "rate_limit_error");
+    assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+    assert!(
+        body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Rate limit exceeded"),
+        "{}",
+        body
+    );
+}
+
+#[actix_web::test]
+async fn openai_chat_completions_stream_chunks() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+
+    let request = test::TestRequest::post()
+        .uri("/v1/chat/completions")
+        .set_json(json!({
+            "messages": [{ "role": "user", "content": "Stream please" }],
+            "stream": true,
+            "stream_options": { "include_usage": true },
+        }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    let chunks = openai_chunks(&body);
+
+    assert!(chunks
+        .iter()
+        .all(|chunk| chunk["object"] == "chat.completion.chunk"));
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    let answer: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(answer, "openrouter got 1 messages: Stream please");
+    let finished = &chunks[chunks.len() - 2];
+    assert_eq!(finished["choices"][0]["finish_reason"], "stop");
+    let usage = chunks.last().unwrap();
+    assert_eq!(usage["choices"], json!([]));
+    assert_eq!(usage["usage"]["total_tokens"], 15);
+}
+
+#[actix_web::test]
+async fn openai_tool_calls_pass_through() {
+    let stub = start_stub();
+    let app = init(state(providers(&stub, Some(STUB_KEY), Some(STUB_KEY)))).await;
+    let tools = json!([{
+        "type": "function",
+        "function": {
+            "name": "get_weather",
+            "parameters": { "type": "object", "properties": { "city": { "type": "string" } } },
+        },
+    }]);
+
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({
+            "messages": [{ "role": "user", "content": "Weather in Bern?" }],
+            "tools": tools,
+            "tool_choice": "auto",
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let message = &body["choices"][0]["message"];
+    assert_eq!(message["content"], Value::Null);
+    assert_eq!(message["tool_calls"][0]["function"]["name"], "get_weather");
+    assert_eq!(body["choices"][0]["finish_reason"], "tool_calls");
+
+    // The client runs the tool and sends the result back.
+    let (status, body) = post_json(
+        &app,
+        "/v1/chat/completions",
+        json!({
+            "messages": [
+                { "role": "user", "content": "Weather in Bern?" },
+                { "role": "assistant", "content": null, "tool_calls": message["tool_calls"] },
+                { "role": "tool", "tool_call_id": "call_1", "content": "Sunny" },
+            ],
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        "openrouter got 3 messages: Weather in Bern?"
+    );
+
+    let request = test::TestRequest::post()
+        .uri("/v1/chat/completions")
+        .set_json(json!({
+            "messages": [{ "role": "user", "content": "Weather in Bern?" }],
+            "tools": tools,
+            "stream": true,
+        }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    let chunks = openai_chunks(&body);
+    assert_eq!(
+        chunks[0]["choices"][0]["delta"]["tool_calls"][0]["id"],
+        "call_1"
+    );
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "tool_calls"
+    );
+}
+
+#[actix_web::test]
+async fn openai_models_lists_enabled_providers() {
+    let policy = ParamPolicy::new(4096).allow_models(
+        "openrouter",
+        ["*".to_string(), "stub/other-model".to_string()],
+    );
+    let state = AppState {
+        params: Arc::new(policy),
+        api_keys: api_keys(
+            "models",
+            &[
+                ("any", ""),
+                ("other-only", "models = [\"stub/other-model\"]"),
+            ],
+        ),
+        ..state(providers("http://127.0.0.1:9", Some(STUB_KEY), None))
+    };
+    let app = init(state).await;
+    let ids = |key: &str| {
+        let request = test::TestRequest::get()
+            .uri("/v1/models")
+            .insert_header(("authorization", format!("Bearer {}", key)))
+            .to_request();
+        let app = &app;
+        async move {
+            let body: Value = test::call_and_read_body_json(app, request).await;
+            assert_eq!(body["object"], "list");
+            body["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|model| model["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(
+        ids("any-key").await,
+        [
+            "openrouter/stub/openrouter-model",
+            "openrouter/stub/other-model"
+        ]
+    );
+    assert_eq!(ids("other-only-key").await, ["openrouter/stub/other-model"]);
+}
@@ -0,0 +1,73 @@
+//! Arguments of the `serve`, `ask` and `chat` commands.
+
+use openrouter_rust_demo::cli::Command;
+
+fn parse(args: &[&str]) -> Result<Command, String> {
+    Command::parse(args.iter().map(|arg| arg.to_string()))
+}
+
+#[test]
+fn no_arguments_start_the_server() {
+    assert!(matches!(parse(&[]), Ok(Command::Serve)));
+    assert!(matches!(parse(&["serve"]), Ok(Command::Serve)));
+    assert!(matches!(parse(&["--help"]), Ok(Command::Help)));
+}
+
+#[test]
+fn ask_takes_the_question_and_prompt_options() {
+    let Ok(Command::Ask(ask)) = parse(&[
+        "ask",
+        "--provider",
+        "groq",
+        "--var=language=French",
+        "--temperature",
+        "0.2",
+        "--no-stream",
+        "Good",
+        "morning",
+    ]) else {
+        panic!("not an ask command");
+    };
+    assert_eq!(ask.question.as_deref(), Some("Good morning"));
+    assert_eq!(ask.prompt.provider, "groq");
+    assert_eq!(ask.prompt.variables["language"], "French");
+    assert_eq!(ask.prompt.params.temperature, Some(0.2));
+    assert!(ask.no_stream);
+
+    let Ok(Command::Ask(ask)) = parse(&["ask"]) else {
+        panic!("not an ask command");
+    };
+    assert!(ask.question.is_none(), "read from stdin instead");
+    assert_eq!(ask.prompt.provider, "openrouter");
+}
+
+#[test]
+fn chat_resumes_conversations() {
+    let Ok(Command::Chat(chat)) = parse(&["chat", "--resume", "abc", "--model=m"]) else {
+        panic!("not a chat command");
+    };
+    assert_eq!(chat.resume.as_deref(), Some("abc"));
+    assert_eq!(chat.prompt.params.model.as_deref(), Some("m"));
+}
+
+#[test]
+fn bad_arguments_are_explained() {
+    for (args, expected) in [
+        (&["launch"][..], "unknown command 'launch'"),
+        (
+            &["ask", "--temprature", "1"],
+            "unknown option '--temprature'",
+        ),
+        (
+            &["ask", "--max-tokens", "many"],
+            "--max-tokens expects a number",
+        ),
+        (&["ask", "--model"], "--model needs a value"),
+        (&["ask", "--var", "language"], "--var expects KEY=VALUE"),
+        (&["chat", "hello"], "chat takes no question"),
+        (&["serve", "now"], "serve takes no arguments"),
+    ] {
+        let error = parse(args).unwrap_err();
+        assert!(error.starts_with(expected), "{:?}: {}", args, error);
+    }
+}
@@ -0,0 +1,180 @@
+//! Layering of defaults, the TOML file, the environment and secret files.
+
+use openrouter_rust_demo::config::{CacheMode, Config};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Applies `vars` as the environment.
+fn with_env(mut config: Config, vars: &[(&str, &str)]) -> Result<Config, String> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    config.apply_env(&|var| vars.get(var).cloned())?;
+    Ok(config)
+}
+
+/// Writes `contents` to a file of its own in the temp dir.
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rustybot-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn defaults_match_the_documented_values() {
+    let config = Config::default();
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.server.static_dir, PathBuf::from("./static"));
+    assert_eq!(config.upstream.timeout_secs, 60);
+    assert_eq!(config.cache.mode, CacheMode::Memory);
+    assert_eq!(config.rate_limits.len(), 5);
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn the_environment_overrides_the_file() {
+    let file = Config::from_toml_str(
+        r#"
+        [server]
+        port = 9000
+        host = "127.0.0.1"
+
+        [providers.openrouter]
+        model = "from/file"
+
+        [rate_limits]
+        "/completion" = "5/60"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(file.rate_limits.len(), 1, "a table replaces the defaults");
+
+    let config = with_env(
+        file,
+        &[
+            ("PORT", "9100"),
+            ("MODEL", "from/env"),
+            ("RESPONSE_CACHE", "off"),
+            ("GROQ_ALLOWED_MODELS", "a, b"),
+            ("RATE_LIMITS", ""),
+        ],
+    )
+    .unwrap();
+    assert_eq!(config.server.port, 9100);
+    assert_eq!(config.server.host, "127.0.0.1");
+    assert_eq!(
+        config.providers.openrouter.model.as_deref(),
+        Some("from/env")
+    );
+    assert_eq!(config.cache.mode, CacheMode::Off);
+    assert_eq!(config.providers.groq.allowed_models, ["a", "b"]);
+    assert!(config.rate_limits.is_empty());
+}
+
+#[test]
+fn api_keys_are_read_from_files_unless_the_environment_has_one() {
+    let key_file = temp_file("groq-key", "gsk-from-file\n");
+    let mut config = Config::from_toml_str(&format!(
+        "[providers.groq]\napi_key_file = {:?}\n",
+        key_file.display().to_string()
+    ))
+    .unwrap();
+    config.resolve_files().unwrap();
+    let key = config.providers.groq.api_key.as_ref().unwrap();
+    assert_eq!(key.expose(), "gsk-from-file");
+    assert_eq!(format!("{:?}", key), "Secret(***)");
+
+    let mut config = with_env(
+        Config::default(),
+        &[
+            ("OPENROUTER_API_KEY_FILE", key_file.to_str().unwrap()),
+            ("OPENROUTER_API_KEY", "sk-from-env"),
+            ("GROQ_API_KEY", "  "),
+        ],
+    )
+    .unwrap();
+    config.resolve_files().unwrap();
+    let key = config.providers.openrouter.api_key.as_ref().unwrap();
+    assert_eq!(key.expose(), "sk-from-env");
+    assert!(
+        config.providers.groq.api_key.is_none(),
+        "blank keys count as unset"
+    );
+    std::fs::remove_file(key_file).unwrap();
+}
+
+#[test]
+fn a_key_and_a_key_file_for_one_provider_are_rejected() {
+    let error = Config::from_toml_str(
+        "[providers.openrouter]\napi_key = \"sk\"\napi_key_file = \"/run/secrets/key\"\n",
+    )
+    .unwrap_err();
+    assert!(error.contains("providers.openrouter"), "{}", error);
+}
+
+#[test]
+fn unreadable_key_files_and_bad_values_name_the_setting() {
+    let mut config = with_env(
+        Config::default(),
+        &[("GROQ_API_KEY_FILE", "/nonexistent/groq-key")],
+    )
+    .unwrap();
+    let error = config.resolve_files().unwrap_err();
+    assert!(
+        error.starts_with("providers.groq.api_key_file"),
+        "{}",
+        error
+    );
+
+    let error = with_env(Config::default(), &[("UPSTREAM_TIMEOUT_SECS", "soon")]).unwrap_err();
+    assert!(
+        error.starts_with("UPSTREAM_TIMEOUT_SECS='soon'"),
+        "{}",
+        error
+    );
+
+    for value in ["TRUE", "yes"] {
+        let error = with_env(Config::default(), &[("CORS_ALLOW_CREDENTIALS", value)]).unwrap_err();
+        assert!(error.starts_with("CORS_ALLOW_CREDENTIALS="), "{}", error);
+    }
+    let config = with_env(Config::default(), &[("CORS_ALLOW_CREDENTIALS", "1")]).unwrap();
+    assert!(config.cors.allow_credentials);
+
+    let error = Config::from_toml_str("[server]\nprot = 1\n").unwrap_err();
+    assert!(error.contains("unknown field `prot`"), "{}", error);
+}
+
+#[test]
+fn validation_reports_every_problem() {
+    let config = with_env(
+        Config::default(),
+        &[
+            ("UPSTREAM_TIMEOUT_SECS", "0"),
+            ("FALLBACK_MODELS", "claude:x"),
+            ("MOCK_PROVIDERS", "groq,other"),
+            ("GROQ_BASE_URL", "localhost:8000"),
+            ("RATE_LIMITS", "/templates=1/60"),
+            ("TRUSTED_PROXIES", "10.0.0.0/8,10.0.0.0/33"),
+            ("SUMMARY_PROMPT_TOKENS", "100"),
+        ],
+    )
+    .unwrap();
+    let error = config.validate().unwrap_err();
+    for expected in [
+        "upstream.timeout_secs",
+        "unknown provider 'claude'",
+        "unknown provider 'other'",
+        "providers.groq.base_url",
+        "cannot rate limit '/templates'",
+        "invalid proxy '10.0.0.0/33'",
+        "summarize.prompt_tokens must be at least 256",
+    ] {
+        assert!(
+            error.contains(expected),
+            "{} missing from {}",
+            expected,
+            error
+        );
+    }
+}
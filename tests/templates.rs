@@ -0,0 +1,78 @@
+//! Startup validation of prompt template files.
+
+use openrouter_rust_demo::templates::PromptLibrary;
+
+fn load_error(contents: &str) -> String {
+    match PromptLibrary::from_toml_str(contents) {
+        Ok(_) => panic!("accepted:\n{}", contents),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn templates_may_only_use_the_question_and_their_defaults() {
+    let error = load_error(
+        r#"
+        default = "translate"
+
+        [templates.translate]
+        user = "Translate into {{language}} for {{audience}}: {{question}}"
+        defaults = { language = "German" }
+        "#,
+    );
+    assert!(
+        error.contains("variable 'audience' is not available here"),
+        "{}",
+        error
+    );
+
+    let error = load_error(
+        r#"
+        default = "missing"
+
+        [templates.concise]
+        user = "{{question}}"
+        "#,
+    );
+    assert!(
+        error.contains("default template is not defined"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn pipeline_steps_may_only_use_earlier_outputs() {
+    let error = load_error(
+        r#"
+        default = "plain"
+
+        [templates.plain]
+        user = "{{question}}"
+
+        [pipelines.refine]
+        steps = [
+            { name = "draft", user = "{{critique}}" },
+            { name = "critique", user = "{{draft}}" },
+        ]
+        "#,
+    );
+    assert!(
+        error.contains("variable 'critique' is not available here"),
+        "{}",
+        error
+    );
+
+    let error = load_error(
+        r#"
+        default = "plain"
+
+        [templates.plain]
+        user = "{{question}}"
+
+        [pipelines.echo]
+        steps = [{ name = "text", user = "{{question}}" }]
+        "#,
+    );
+    assert!(error.contains("step name 'text' is reserved"), "{}", error);
+}